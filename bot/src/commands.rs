@@ -1,20 +1,22 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
     Context, Error,
-    runner::handle_benchmark,
-    utils::{aoc_today, get_name},
+    database::{Category, Language, Metric, Mode, Phase, Ranking, Size},
+    queue,
+    utils::{aoc_today, day_closed, get_name},
 };
 
 use poise::{
-    CreateReply,
+    ChoiceParameter, CreateReply,
     serenity_prelude::{
-        self as serenity, CreateEmbed,
+        self as serenity, CreateAttachment, CreateEmbed,
         futures::{StreamExt, stream},
     },
 };
+use worker::Shape;
 
-#[poise::command(slash_command, subcommands("input", "run", "leaderboard"))]
+#[poise::command(slash_command, subcommands("input", "run", "leaderboard", "mode"))]
 pub async fn aoc(_: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
@@ -24,16 +26,18 @@ pub async fn input(
     ctx: Context<'_>,
     #[description = "File containing the input for the day."] file: serenity::Attachment,
     #[description = "The day this input is for. Defaults to today."] day: Option<u8>,
+    #[description = "The year this input is for. Defaults to this year."] year: Option<i32>,
 ) -> Result<(), Error> {
-    let today = aoc_today();
+    let (this_year, today) = aoc_today();
+    let year = year.unwrap_or(this_year);
     let day = day.unwrap_or(today);
-    if day > today {
+    if (year, day) > (this_year, today) {
         ctx.say("Cannot accept input from the future!").await?;
         return Ok(());
     }
 
     let database = &ctx.data().database;
-    if database.inputs_count(day).await? >= 3 {
+    if database.inputs_count(year, day).await? >= 3 {
         ctx.say("There's enough inputs for today! Thank you anyway!")
             .await?;
         return Ok(());
@@ -42,18 +46,18 @@ pub async fn input(
     let user = ctx.author().id;
     let input = file.download().await?;
 
-    let (_, inputs) = database.fetch_inputs(day, 3).await?;
-    if inputs.iter().any(|i| *i == input) {
+    let (_, inputs) = database.fetch_inputs(year, day, 3).await?;
+    if inputs.contains(&input) {
         ctx.say("Already have this input! Thank you anyway!")
             .await?;
         return Ok(());
     }
 
-    database.insert_input(user, day, &input).await?;
+    database.insert_input(user, year, day, &input).await?;
 
     let sender = &ctx.data().input_watch;
     if !sender.is_closed() {
-        sender.send(day)?;
+        sender.send(())?;
     }
 
     ctx.say("Thank you for your input!").await?;
@@ -66,51 +70,164 @@ async fn run(
     ctx: Context<'_>,
     #[description = "File containing the code to run."] file: serenity::Attachment,
     #[description = "The day this code is for. Defaults to today."] day: Option<u8>,
-    #[description = "The part this code is for."] part: u8,
+    #[description = "The part this code is for. Leave empty if it defines part1 and part2."]
+    part: Option<u8>,
+    #[description = "The year this code is for. Defaults to this year."] year: Option<i32>,
+    #[description = "The language the code is in. Defaults to the file's extension."]
+    language: Option<Language>,
 ) -> Result<(), Error> {
-    let today = aoc_today();
+    let (this_year, today) = aoc_today();
+    let year = year.unwrap_or(this_year);
     let day = day.unwrap_or(today);
-    if day > today {
+    if (year, day) > (this_year, today) {
         ctx.say("Cannot run from the future!").await?;
         return Ok(());
     };
 
+    let Some(language) = language.or_else(|| Language::from_file_name(&file.filename)) else {
+        ctx.say("Please choose the language this code is in.")
+            .await?;
+        return Ok(());
+    };
+
     let user = ctx.author().id;
 
     let code = file.download().await?;
 
+    // Code solving both parts is scored for each of them regardless of the part given
+    let part = if Shape::detect(language.into(), &code).solves_both() {
+        None
+    } else {
+        match part {
+            Some(part @ (1 | 2)) => Some(part),
+            Some(_) => {
+                ctx.say("There are only two parts!").await?;
+                return Ok(());
+            }
+            None => {
+                ctx.say(
+                    "Please choose the part this code is for, or define both `part1` and `part2`.",
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+    };
+
+    // Replied to first so that the run can keep the reply up to date from the start
+    let reply = ctx.say("Your submission has been queued.").await?;
+    let reply = reply.message().await?;
+
+    let rid = ctx
+        .data()
+        .database
+        .insert_run(
+            user,
+            year,
+            day,
+            part,
+            &code,
+            language,
+            Some((reply.channel_id, reply.id)),
+        )
+        .await?;
+
     let http = Arc::clone(&ctx.serenity_context().http);
     let data = Arc::clone(ctx.data());
-    tokio::spawn(async move { handle_benchmark(&http, &data, user, day, part, code).await });
-
-    ctx.say("Your submission has been queued.").await?;
+    queue::enqueue(http, data, rid).await?;
 
     Ok(())
 }
 
 #[poise::command(slash_command)]
+#[allow(clippy::too_many_arguments)]
 pub async fn leaderboard(
     ctx: Context<'_>,
     #[description = ""] day: Option<u8>,
+    #[description = "The year of the leaderboard. Defaults to this year."] year: Option<i32>,
+    #[description = "What to rank runs by. Defaults to the total."] phase: Option<Phase>,
+    #[description = "What to measure runs in. Defaults to instructions."] metric: Option<Metric>,
+    #[description = "Whether to rank by wall clock. Defaults to how the day is benchmarked."]
+    mode: Option<Mode>,
+    #[description = "What kind of leaderboard. Defaults to speed."] category: Option<Category>,
+    #[description = "Only rank runs in this language. Defaults to every language."]
+    language: Option<Language>,
 ) -> Result<(), Error> {
-    let today = aoc_today();
+    let (this_year, today) = aoc_today();
+    let year = year.unwrap_or(this_year);
     let day = day.unwrap_or(today);
-    if day > today {
+    if (year, day) > (this_year, today) {
         ctx.say("Cannot get leaderboard from the future!").await?;
         return Ok(());
     }
 
-    let (part1, part2) = ctx.data().database.fetch_scores_for_day(day).await?;
+    let database = &ctx.data().database;
+    let ranking = match category.unwrap_or_default() {
+        Category::Speed => {
+            let phase = phase.unwrap_or_default();
+            let metric = metric.unwrap_or_default();
+            if phase != Phase::Total && metric != Metric::Instructions {
+                ctx.say("Parsing and solving are only measured in instructions.")
+                    .await?;
+                return Ok(());
+            }
+            let mode = match mode {
+                Some(mode) => mode,
+                // Asking for anything callgrind measures implies instructions
+                None if phase != Phase::Total || metric != Metric::Instructions => {
+                    Mode::Instructions
+                }
+                None => database.fetch_day_mode(year, day).await?,
+            };
+            match (mode, metric) {
+                (Mode::WallClock, Metric::Instructions) if phase == Phase::Total => {
+                    Ranking::WallClock
+                }
+                (Mode::WallClock, _) => {
+                    ctx.say("Wall clock leaderboards only rank the total time.")
+                        .await?;
+                    return Ok(());
+                }
+                (Mode::Instructions, Metric::Instructions) => Ranking::Instructions(phase),
+                (Mode::Instructions, metric) => Ranking::Metric(metric),
+            }
+        }
+        _ if phase.is_some() || metric.is_some() || mode.is_some() => {
+            ctx.say("Only speed leaderboards can be broken down further.")
+                .await?;
+            return Ok(());
+        }
+        Category::Memory => Ranking::PeakHeap,
+        Category::CodeGolf => Ranking::CodeSize(Size::Bytes),
+        Category::CodeGolfTokens => Ranking::CodeSize(Size::Tokens),
+        Category::CompileTime => Ranking::CompileTime,
+        Category::CodegenUnits => Ranking::CodegenUnits,
+        Category::BinarySize => Ranking::TextSize,
+    };
+
+    let (part1, part2) = database
+        .fetch_scores_for_day(year, day, ranking, language)
+        .await?;
     // Assume if theres no part 1, then there couldn't be a part 2
     if part1.is_empty() {
         ctx.say("No runs on the leaderboard yet. Be the first!")
             .await?;
         return Ok(());
     }
+    let show = move |score: f64| match ranking {
+        Ranking::WallClock | Ranking::CompileTime => {
+            format!("{:?}", Duration::from_nanos(score as u64))
+        }
+        Ranking::PeakHeap | Ranking::CodeSize(Size::Bytes) | Ranking::TextSize => {
+            format!("{score} bytes")
+        }
+        Ranking::CodeSize(Size::Tokens) => format!("{score} tokens"),
+        Ranking::Instructions(_) | Ranking::Metric(_) | Ranking::CodegenUnits => score.to_string(),
+    };
     let part1 = stream::iter(part1)
         .then(|score| async move {
             let name = get_name(&ctx, score.user).await;
-            format!("\t{}: **{}**\n", name, score.score)
+            format!("\t{}: **{}**\n", name, show(score.score))
         })
         .collect::<String>()
         .await;
@@ -118,7 +235,7 @@ pub async fn leaderboard(
         stream::iter(part2)
             .then(|score| async move {
                 let name = get_name(&ctx, score.user).await;
-                format!("\t{}: **{}**\n", name, score.score)
+                format!("\t{}: **{}**\n", name, show(score.score))
             })
             .collect::<String>()
             .await
@@ -126,12 +243,87 @@ pub async fn leaderboard(
         "**None**".to_owned()
     };
 
-    let embed = CreateEmbed::new()
-        .title(format!("Top 10 Fastest Toboggans For Day {day}"))
+    let title = match ranking {
+        Ranking::Instructions(Phase::Total) => "Top 10 Fastest Toboggans".to_owned(),
+        Ranking::Instructions(Phase::Parse) => "Top 10 Fastest Parsers".to_owned(),
+        Ranking::Instructions(Phase::Solve) => "Top 10 Fastest Solvers".to_owned(),
+        Ranking::Metric(metric) => format!("Top 10 Toboggans By {}", metric.name()),
+        Ranking::WallClock => "Top 10 Fastest Toboggans By Wall Clock".to_owned(),
+        Ranking::PeakHeap => "Top 10 Leanest Toboggans".to_owned(),
+        Ranking::CodeSize(Size::Bytes) => "Top 10 Shortest Toboggans".to_owned(),
+        Ranking::CodeSize(Size::Tokens) => "Top 10 Toboggans By Fewest Tokens".to_owned(),
+        Ranking::CompileTime => "Top 10 Fastest Compiling Toboggans".to_owned(),
+        Ranking::CodegenUnits => "Top 10 Toboggans By Fewest Codegen Units".to_owned(),
+        Ranking::TextSize => "Top 10 Smallest Toboggans".to_owned(),
+    };
+    let title = match language {
+        Some(language) => format!("{title} In {}", language.name()),
+        None => title,
+    };
+    let mut embed = CreateEmbed::new()
+        .title(format!("{title} For Day {day} of {year}"))
         .colour(0xE84611)
         .field("Part 1", part1, true)
         .field("Part 2", part2, true);
-    ctx.send(CreateReply::default().embed(embed)).await?;
+    let mut reply = CreateReply::default();
+
+    // Kept secret until nobody can copy them anymore
+    if let Ranking::CodeSize(_) = ranking
+        && day_closed(year, day)
+    {
+        let mut shortest = Vec::new();
+        let mut attached = Vec::new();
+        for part in [1, 2] {
+            let Some(rid) = database
+                .fetch_best_run(year, day, part, ranking, language)
+                .await?
+            else {
+                continue;
+            };
+            let run = database.fetch_run(rid).await?;
+            let name = get_name(&ctx, run.submitter).await;
+            shortest.push(format!("Part {part} by {name}"));
+            // Code solving both parts may be the shortest for both
+            if !attached.contains(&rid) {
+                let extension = run.language.extension();
+                let file = match run.part {
+                    Some(part) => format!("day{day}_part{part}.{extension}"),
+                    None => format!("day{day}.{extension}"),
+                };
+                reply = reply.attachment(CreateAttachment::bytes(run.code, file));
+                attached.push(rid);
+            }
+        }
+        embed = embed.field("Shortest Correct Solutions", shortest.join("\n"), false);
+    }
+    ctx.send(reply.embed(embed)).await?;
+
+    Ok(())
+}
+
+/// Picks how runs for a day are benchmarked from now on
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+async fn mode(
+    ctx: Context<'_>,
+    #[description = "How to benchmark runs."] mode: Mode,
+    #[description = "The day to benchmark this way. Defaults to today."] day: Option<u8>,
+    #[description = "The year of the day. Defaults to this year."] year: Option<i32>,
+) -> Result<(), Error> {
+    let (this_year, today) = aoc_today();
+    let year = year.unwrap_or(this_year);
+    let day = day.unwrap_or(today);
+
+    // Runs already benchmarked keep the results they have
+    ctx.data().database.set_day_mode(year, day, mode).await?;
+
+    let how = match mode {
+        Mode::Instructions => "by instructions",
+        Mode::WallClock => "by instructions and wall clock",
+    };
+    ctx.say(format!(
+        "Runs for day {day} of {year} will be benchmarked {how}."
+    ))
+    .await?;
 
     Ok(())
 }
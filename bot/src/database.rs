@@ -1,10 +1,12 @@
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
 
-use poise::serenity_prelude::UserId;
+use poise::serenity_prelude::{ChannelId, MessageId, UserId};
 use sqlx::{
-    Row, SqlitePool,
+    Row, SqliteConnection, SqlitePool,
     sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
 };
+use time::OffsetDateTime;
+use worker::{Build, Timing};
 
 use crate::Error;
 
@@ -17,128 +19,741 @@ pub struct Score {
     pub score: f64,
 }
 
-impl Database {
-    pub async fn init(path: &str) -> Result<Self, Error> {
-        let url = format!("sqlite://{path}");
-        let options = SqliteConnectOptions::from_str(&url)?
-            .create_if_missing(true)
-            .journal_mode(SqliteJournalMode::Wal)
-            .synchronous(SqliteSynchronous::Normal)
-            .optimize_on_close(true, None);
-        let pool = SqlitePool::connect_with(options).await?;
+/// Which part of a run's instructions a leaderboard ranks by
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Phase {
+    #[default]
+    Total,
+    #[name = "Parse only"]
+    Parse,
+    #[name = "Solve only"]
+    Solve,
+}
 
-        {
-            let mut tx = pool.begin().await?;
+impl Phase {
+    fn column(self) -> &'static str {
+        match self {
+            Phase::Total => "score",
+            Phase::Parse => "parse_score",
+            Phase::Solve => "solve_score",
+        }
+    }
+}
 
-            sqlx::query(
-                "
+/// How runs for a day are benchmarked, which is also what their leaderboard ranks by unless
+/// asked otherwise
+#[derive(poise::ChoiceParameter, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[sqlx(rename_all = "snake_case")]
+pub enum Mode {
+    /// Counted under callgrind, which every run is
+    #[default]
+    Instructions,
+    /// Timed natively as well
+    #[name = "Wall clock"]
+    WallClock,
+}
+
+/// What kind of leaderboard to show
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Category {
+    #[default]
+    Speed,
+    #[name = "Lowest memory"]
+    Memory,
+    #[name = "Code golf"]
+    CodeGolf,
+    #[name = "Code golf by tokens"]
+    CodeGolfTokens,
+    #[name = "Compile time"]
+    CompileTime,
+    #[name = "Codegen units"]
+    CodegenUnits,
+    #[name = "Binary size"]
+    BinarySize,
+}
+
+/// What a submission is written in
+#[derive(poise::ChoiceParameter, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[sqlx(rename_all = "snake_case")]
+pub enum Language {
+    #[default]
+    Rust,
+    C,
+    #[name = "C++"]
+    Cpp,
+    Zig,
+}
+
+impl Language {
+    /// Guesses the language from the extension of a file
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        match name.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+            "rs" => Some(Language::Rust),
+            "c" => Some(Language::C),
+            "cpp" | "cc" | "cxx" => Some(Language::Cpp),
+            "zig" => Some(Language::Zig),
+            _ => None,
+        }
+    }
+
+    /// The extension its source files usually have
+    pub fn extension(self) -> &'static str {
+        match self {
+            Language::Rust => "rs",
+            Language::C => "c",
+            Language::Cpp => "cpp",
+            Language::Zig => "zig",
+        }
+    }
+}
+
+impl From<Language> for worker::Language {
+    fn from(language: Language) -> Self {
+        match language {
+            Language::Rust => worker::Language::Rust,
+            Language::C => worker::Language::C,
+            Language::Cpp => worker::Language::Cpp,
+            Language::Zig => worker::Language::Zig,
+        }
+    }
+}
+
+/// How code golf leaderboards measure code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Size {
+    Bytes,
+    /// Leaves out whitespace and comments
+    Tokens,
+}
+
+/// Which callgrind event a leaderboard ranks by. Everything but instructions needs the
+/// cache simulation, which admins may have left off.
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Metric {
+    #[default]
+    Instructions,
+    #[name = "Estimated cycles"]
+    EstimatedCycles,
+    #[name = "L1 hits"]
+    L1Hits,
+    #[name = "Last level hits"]
+    LlHits,
+    #[name = "RAM hits"]
+    RamHits,
+    #[name = "Total reads and writes"]
+    TotalRw,
+    #[name = "Instruction cache misses"]
+    I1Misses,
+    #[name = "Data cache read misses"]
+    D1ReadMisses,
+    #[name = "Data cache write misses"]
+    D1WriteMisses,
+    #[name = "Last level instruction misses"]
+    LlInstructionMisses,
+    #[name = "Last level read misses"]
+    LlReadMisses,
+    #[name = "Last level write misses"]
+    LlWriteMisses,
+}
+
+impl Metric {
+    /// The name iai-callgrind gives the event
+    fn event(self) -> &'static str {
+        match self {
+            Metric::Instructions => "Ir",
+            Metric::EstimatedCycles => "EstimatedCycles",
+            Metric::L1Hits => "L1hits",
+            Metric::LlHits => "LLhits",
+            Metric::RamHits => "RamHits",
+            Metric::TotalRw => "TotalRW",
+            Metric::I1Misses => "I1mr",
+            Metric::D1ReadMisses => "D1mr",
+            Metric::D1WriteMisses => "D1mw",
+            Metric::LlInstructionMisses => "ILmr",
+            Metric::LlReadMisses => "DLmr",
+            Metric::LlWriteMisses => "DLmw",
+        }
+    }
+}
+
+/// What a leaderboard ranks runs by, lowest first
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Ranking {
+    /// Instructions spent in a phase
+    Instructions(Phase),
+    /// Any other event callgrind counted, over the whole run
+    Metric(Metric),
+    /// The median wall-clock time in nanoseconds
+    WallClock,
+    /// The peak heap size in bytes
+    PeakHeap,
+    /// The size of the code
+    CodeSize(Size),
+    /// How long the code took to compile in nanoseconds
+    CompileTime,
+    /// How many codegen units the code was split into
+    CodegenUnits,
+    /// The size of the benchmark's `.text` in bytes
+    TextSize,
+}
+
+impl Ranking {
+    /// A query for the score of every part that matched the consensus, with the columns
+    /// `run_id`, `part` and `score`
+    fn scores(self) -> String {
+        match self {
+            Ranking::Instructions(phase) => format!(
+                "SELECT run_id, part, {} AS score FROM run_scores",
+                phase.column()
+            ),
+            // Averaged over the inputs like the scores
+            Ranking::Metric(metric) => format!(
+                "SELECT run_metrics.run_id, run_metrics.part, CAST(AVG(value) AS INTEGER) AS score
+                    FROM run_metrics
+                    JOIN run_scores ON run_scores.run_id = run_metrics.run_id
+                        AND run_scores.part = run_metrics.part
+                    WHERE metric = '{}'
+                    GROUP BY run_metrics.run_id, run_metrics.part",
+                metric.event()
+            ),
+            Ranking::WallClock => {
+                "SELECT run_id, part, median_time AS score FROM run_scores".to_owned()
+            }
+            Ranking::PeakHeap => {
+                "SELECT run_id, part, peak_heap AS score FROM run_scores".to_owned()
+            }
+            Ranking::CodeSize(Size::Bytes) => {
+                "SELECT run_id, run_scores.part, LENGTH(code) AS score
+                    FROM run_scores
+                    JOIN runs ON runs.id = run_scores.run_id"
+                    .to_owned()
+            }
+            Ranking::CodeSize(Size::Tokens) => {
+                "SELECT run_id, part, code_tokens AS score FROM run_scores".to_owned()
+            }
+            Ranking::CompileTime => build_scores("compile_time"),
+            Ranking::CodegenUnits => build_scores("codegen_units"),
+            Ranking::TextSize => build_scores("text_size"),
+        }
+    }
+}
+
+/// Scores from what building a run produced, which is the same for both parts
+fn build_scores(column: &str) -> String {
+    format!(
+        "SELECT run_id, run_scores.part, {column} AS score
+            FROM run_scores
+            JOIN runs ON runs.id = run_scores.run_id"
+    )
+}
+
+#[derive(sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+pub enum RunStatus {
+    Queued,
+    WaitingForInputs,
+    Building,
+    Benchmarking,
+    AwaitingConsensus,
+    WrongAnswer,
+    Failed,
+    Scored,
+}
+
+pub struct Run {
+    pub id: i64,
+    pub submitter: UserId,
+    pub year: i32,
+    pub day: u8,
+    /// The part the code solves, or `None` when it solves both
+    pub part: Option<u8>,
+    pub code: Vec<u8>,
+    pub language: Language,
+    /// The reply to the submission, which shows how the run is getting on
+    pub reply: Option<(ChannelId, MessageId)>,
+}
+
+#[derive(sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+pub struct Job {
+    pub run_id: i64,
+    /// How many times it was claimed without finishing
+    pub attempts: u32,
+}
+
+/// The outcome of a run on a single input for one part
+pub struct RunResult {
+    pub part: u8,
+    pub input_id: i64,
+    pub answer: String,
+    pub score: u64,
+    pub parse_score: Option<u64>,
+    pub solve_score: Option<u64>,
+    /// Every event callgrind counted, keyed by the name iai-callgrind gives it
+    pub metrics: BTreeMap<String, u64>,
+    /// Only taken for days benchmarked by wall clock
+    pub timing: Option<Timing>,
+    /// In bytes, missing for runs from before it was measured
+    pub peak_heap: Option<u64>,
+}
+
+/// The averages over the inputs of a part whose answers matched the consensus
+pub struct PartScore {
+    pub score: i64,
+    pub parse_score: Option<i64>,
+    pub solve_score: Option<i64>,
+    /// In nanoseconds
+    pub median_time: Option<i64>,
+    /// In bytes
+    pub peak_heap: Option<i64>,
+    /// Missing if the code could not be lexed
+    pub code_tokens: Option<i64>,
+}
+
+/// The version of the schema, kept in `PRAGMA user_version`. Bumped along with a step in
+/// [`Database::migrate`] whenever the schema changes in a way creating missing tables
+/// doesn't cover. Databases from before versions were kept are at 0.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Every table as this version of the bot creates it
+const TABLES: [&str; 8] = [
+    "
 CREATE TABLE IF NOT EXISTS inputs(
     id INTEGER PRIMARY KEY,
+    year INTEGER,
     day INTEGER,
     submitter INTEGER,
     data BLOB
 )",
-            )
-            .execute(&mut *tx)
-            .await?;
-
-            sqlx::query(
-                "
+    "
 CREATE TABLE IF NOT EXISTS solutions(
     id INTEGER PRIMARY KEY,
     input_id INTEGER,
     part INTEGER,
     submitter INTEGER,
-    answer INTEGER,
+    answer TEXT,
     FOREIGN KEY (input_id)
         REFERENCES inputs(id)
         ON DELETE CASCADE
         ON UPDATE SET NULL
 )",
-            )
-            .execute(&mut *tx)
-            .await?;
-
-            sqlx::query(
-                "
+    "
 CREATE TABLE IF NOT EXISTS runs(
     id INTEGER PRIMARY KEY,
     submitter INTEGER,
+    year INTEGER,
     day INTEGER,
     part INTEGER,
+    code BLOB,
+    language TEXT,
+    reply_channel INTEGER,
+    reply_message INTEGER,
+    status TEXT,
+    error TEXT,
+    compile_time INTEGER,
+    codegen_units INTEGER,
+    text_size INTEGER,
+    created_at INTEGER,
+    updated_at INTEGER
+)",
+    "
+CREATE TABLE IF NOT EXISTS run_results(
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER,
+    part INTEGER,
+    input_id INTEGER,
+    answer TEXT,
+    score INTEGER,
+    parse_score INTEGER,
+    solve_score INTEGER,
+    median_time INTEGER,
+    time_spread INTEGER,
+    time_samples INTEGER,
+    peak_heap INTEGER,
+    FOREIGN KEY (run_id)
+        REFERENCES runs(id)
+        ON DELETE CASCADE,
+    FOREIGN KEY (input_id)
+        REFERENCES inputs(id)
+        ON DELETE CASCADE
+)",
+    "
+CREATE TABLE IF NOT EXISTS run_metrics(
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER,
+    part INTEGER,
+    input_id INTEGER,
+    metric TEXT,
+    value INTEGER,
+    UNIQUE (run_id, part, input_id, metric),
+    FOREIGN KEY (run_id)
+        REFERENCES runs(id)
+        ON DELETE CASCADE,
+    FOREIGN KEY (input_id)
+        REFERENCES inputs(id)
+        ON DELETE CASCADE
+)",
+    "
+CREATE TABLE IF NOT EXISTS run_scores(
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER,
+    part INTEGER,
     score NUMERIC,
-    code BLOB
+    parse_score NUMERIC,
+    solve_score NUMERIC,
+    median_time NUMERIC,
+    peak_heap NUMERIC,
+    code_tokens NUMERIC,
+    UNIQUE (run_id, part),
+    FOREIGN KEY (run_id)
+        REFERENCES runs(id)
+        ON DELETE CASCADE
+)",
+    "
+CREATE TABLE IF NOT EXISTS days(
+    id INTEGER PRIMARY KEY,
+    year INTEGER,
+    day INTEGER,
+    mode TEXT,
+    UNIQUE (year, day)
 )",
+    "
+CREATE TABLE IF NOT EXISTS jobs(
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER,
+    state TEXT,
+    attempts INTEGER DEFAULT 0,
+    lease_expires INTEGER,
+    FOREIGN KEY (run_id)
+        REFERENCES runs(id)
+        ON DELETE CASCADE
+)",
+];
+
+impl Database {
+    /// Opens the database at `path`, creating it or bringing it up to date as needed. Data
+    /// from before years were kept is taken to be from `legacy_year`.
+    pub async fn init(path: &str, legacy_year: i32) -> Result<Self, Error> {
+        let url = format!("sqlite://{path}");
+        let options = SqliteConnectOptions::from_str(&url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .optimize_on_close(true, None);
+        let pool = SqlitePool::connect_with(options).await?;
+        Self::migrate(&pool, legacy_year).await?;
+        Ok(Self(pool))
+    }
+
+    /// A fresh database that is gone once dropped
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self, Error> {
+        let pool = tests::memory().await;
+        Self::migrate(&pool, 2015).await?;
+        Ok(Self(pool))
+    }
+
+    /// Creates the tables, first changing those of older versions of the bot to match
+    async fn migrate(pool: &SqlitePool, legacy_year: i32) -> Result<(), Error> {
+        let mut tx = pool.begin().await?;
+
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&mut *tx)
+            .await?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "The database has schema version {version}, this bot only knows up to {SCHEMA_VERSION}"
             )
+            .into());
+        }
+        let tables: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'")
+                .fetch_one(&mut *tx)
+                .await?;
+        if version == 0 && tables > 0 {
+            upgrade_unversioned(&mut tx, legacy_year).await?;
+        }
+
+        for table in TABLES {
+            sqlx::query(table).execute(&mut *tx).await?;
+        }
+        sqlx::query(&format!("PRAGMA user_version = {SCHEMA_VERSION}"))
             .execute(&mut *tx)
             .await?;
 
-            tx.commit().await?;
-        }
-        Ok(Self(pool))
+        tx.commit().await?;
+        Ok(())
     }
 
-    pub async fn insert_input(&self, user: UserId, day: u8, input: &[u8]) -> Result<(), Error> {
-        sqlx::query("INSERT INTO inputs (day, submitter, data) VALUES(?, ?, ?)")
-            .bind(user.get() as i64)
+    pub async fn insert_input(
+        &self,
+        user: UserId,
+        year: i32,
+        day: u8,
+        input: &[u8],
+    ) -> Result<(), Error> {
+        sqlx::query("INSERT INTO inputs (year, day, submitter, data) VALUES(?, ?, ?, ?)")
+            .bind(year)
             .bind(day)
+            .bind(user.get() as i64)
             .bind(input)
             .execute(&self.0)
             .await?;
         Ok(())
     }
 
-    pub async fn insert_solution(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_run(
         &self,
         user: UserId,
-        input: i64,
-        part: u8,
-        answer: i64,
-    ) -> Result<(), Error> {
+        year: i32,
+        day: u8,
+        part: Option<u8>,
+        code: &[u8],
+        language: Language,
+        reply: Option<(ChannelId, MessageId)>,
+    ) -> Result<i64, Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let res = sqlx::query(
+            "INSERT INTO runs (submitter, year, day, part, code, language, reply_channel,
+                    reply_message, status, created_at, updated_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(user.get() as i64)
+        .bind(year)
+        .bind(day)
+        .bind(part)
+        .bind(code)
+        .bind(language)
+        .bind(reply.map(|(channel, _)| channel.get() as i64))
+        .bind(reply.map(|(_, message)| message.get() as i64))
+        .bind(RunStatus::Queued)
+        .bind(now)
+        .bind(now)
+        .execute(&self.0)
+        .await?;
+        Ok(res.last_insert_rowid())
+    }
+
+    pub async fn fetch_run(&self, id: i64) -> Result<Run, Error> {
+        let row = sqlx::query(
+            "SELECT submitter, year, day, part, code, reply_channel, reply_message, language
+                FROM runs WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_one(&self.0)
+        .await?;
+        let channel = row.get::<Option<i64>, _>(5);
+        let message = row.get::<Option<i64>, _>(6);
+        Ok(Run {
+            id,
+            submitter: (row.get::<i64, _>(0) as u64).into(),
+            year: row.get(1),
+            day: row.get(2),
+            part: row.get(3),
+            code: row.get(4),
+            // Runs from before other languages were supported are all Rust
+            language: row.get::<Option<Language>, _>(7).unwrap_or_default(),
+            reply: channel
+                .zip(message)
+                .map(|(channel, message)| ((channel as u64).into(), (message as u64).into())),
+        })
+    }
+
+    /// Where a run is at, along with why it failed if it did
+    #[cfg(test)]
+    pub async fn fetch_run_status(&self, id: i64) -> Result<(RunStatus, Option<String>), Error> {
+        let row = sqlx::query("SELECT status, error FROM runs WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.0)
+            .await?;
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Records the score of a part whose answers matched the consensus, replacing any
+    /// score recorded by an earlier attempt at the same run
+    pub async fn insert_score(&self, run: i64, part: u8, score: &PartScore) -> Result<(), Error> {
         sqlx::query(
-            "INSERT INTO solutions (input_id, part, submitter, answer) VALUES (?, ?, ?, ?)",
+            "INSERT OR REPLACE INTO run_scores
+                (run_id, part, score, parse_score, solve_score, median_time, peak_heap,
+                    code_tokens)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
         )
-        .bind(input)
+        .bind(run)
         .bind(part)
-        .bind(user.get() as i64)
-        .bind(answer)
+        .bind(score.score)
+        .bind(score.parse_score)
+        .bind(score.solve_score)
+        .bind(score.median_time)
+        .bind(score.peak_heap)
+        .bind(score.code_tokens)
         .execute(&self.0)
         .await?;
         Ok(())
     }
 
-    pub async fn insert_run(
+    /// Records what building the run took and produced
+    pub async fn set_run_build(&self, id: i64, build: &Build) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE runs SET compile_time = ?, codegen_units = ?, text_size = ? WHERE id = ?",
+        )
+        .bind(build.compile_time.as_nanos() as i64)
+        .bind(build.codegen_units)
+        .bind(build.text_size as i64)
+        .bind(id)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// What building the run took and produced, missing for runs that were never built or
+    /// were built before it was recorded
+    pub async fn fetch_run_build(&self, id: i64) -> Result<Option<Build>, Error> {
+        let row =
+            sqlx::query("SELECT compile_time, codegen_units, text_size FROM runs WHERE id = ?")
+                .bind(id)
+                .fetch_one(&self.0)
+                .await?;
+        Ok(row.get::<Option<i64>, _>(0).map(|compile_time| Build {
+            compile_time: Duration::from_nanos(compile_time as u64),
+            codegen_units: row.get(1),
+            text_size: row.get::<i64, _>(2) as u64,
+        }))
+    }
+
+    /// Moves a run along its lifecycle, recording why it stopped if it did not succeed
+    pub async fn set_run_status(
         &self,
-        user: UserId,
-        day: u8,
-        part: u8,
-        code: &[u8],
-    ) -> Result<i64, Error> {
-        let res = sqlx::query("INSERT INTO runs (submitter, day, part, code) VALUES (?, ?, ?, ?)")
-            .bind(user.get() as i64)
-            .bind(day)
-            .bind(part)
-            .bind(code)
+        id: i64,
+        status: RunStatus,
+        error: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query("UPDATE runs SET status = ?, error = ?, updated_at = ? WHERE id = ?")
+            .bind(status)
+            .bind(error)
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .bind(id)
             .execute(&self.0)
             .await?;
-        Ok(res.last_insert_rowid())
+        Ok(())
     }
 
-    pub async fn update_run(&self, id: i64, score: i64) -> Result<(), Error> {
-        sqlx::query("UPDATE runs SET score = ? WHERE id = ? LIMIT 1")
-            .bind(score)
-            .bind(id)
-            .execute(&self.0)
+    /// Stores the per input results of a run along with the solutions it produced,
+    /// so that a resumed run does not need to be benchmarked again.
+    pub async fn insert_run_results(
+        &self,
+        run: i64,
+        user: UserId,
+        results: &[RunResult],
+    ) -> Result<(), Error> {
+        let mut tx = self.0.begin().await?;
+        for res in results {
+            sqlx::query(
+                "INSERT INTO solutions (input_id, part, submitter, answer) VALUES (?, ?, ?, ?)",
+            )
+            .bind(res.input_id)
+            .bind(res.part)
+            .bind(user.get() as i64)
+            .bind(&res.answer)
+            .execute(&mut *tx)
             .await?;
+            sqlx::query(
+                "INSERT INTO run_results
+                    (run_id, part, input_id, answer, score, parse_score, solve_score,
+                        median_time, time_spread, time_samples, peak_heap)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(run)
+            .bind(res.part)
+            .bind(res.input_id)
+            .bind(&res.answer)
+            .bind(res.score as i64)
+            .bind(res.parse_score.map(|s| s as i64))
+            .bind(res.solve_score.map(|s| s as i64))
+            .bind(res.timing.map(|t| t.median.as_nanos() as i64))
+            .bind(res.timing.map(|t| t.spread.as_nanos() as i64))
+            .bind(res.timing.map(|t| t.samples))
+            .bind(res.peak_heap.map(|p| p as i64))
+            .execute(&mut *tx)
+            .await?;
+            for (metric, &value) in &res.metrics {
+                sqlx::query(
+                    "INSERT INTO run_metrics (run_id, part, input_id, metric, value)
+                        VALUES (?, ?, ?, ?, ?)",
+                )
+                .bind(run)
+                .bind(res.part)
+                .bind(res.input_id)
+                .bind(metric)
+                .bind(value as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
         Ok(())
     }
 
+    pub async fn fetch_run_results(&self, run: i64) -> Result<Vec<RunResult>, Error> {
+        let res = sqlx::query(
+            "SELECT part, input_id, answer, score, parse_score, solve_score, median_time,
+                    time_spread, time_samples, peak_heap
+                FROM run_results WHERE run_id = ? ORDER BY id",
+        )
+        .bind(run)
+        .fetch_all(&self.0)
+        .await?;
+        let metrics =
+            sqlx::query("SELECT part, input_id, metric, value FROM run_metrics WHERE run_id = ?")
+                .bind(run)
+                .fetch_all(&self.0)
+                .await?;
+
+        let mut results = res
+            .iter()
+            .map(|row| RunResult {
+                part: row.get(0),
+                input_id: row.get(1),
+                answer: row.get(2),
+                score: row.get::<i64, _>(3) as u64,
+                parse_score: row.get::<Option<i64>, _>(4).map(|s| s as u64),
+                solve_score: row.get::<Option<i64>, _>(5).map(|s| s as u64),
+                metrics: BTreeMap::new(),
+                timing: row.get::<Option<i64>, _>(6).map(|median| Timing {
+                    median: Duration::from_nanos(median as u64),
+                    spread: Duration::from_nanos(row.get::<i64, _>(7) as u64),
+                    samples: row.get(8),
+                }),
+                peak_heap: row.get::<Option<i64>, _>(9).map(|p| p as u64),
+            })
+            .collect::<Vec<_>>();
+        for row in &metrics {
+            let (part, input_id) = (row.get::<u8, _>(0), row.get::<i64, _>(1));
+            if let Some(res) = results
+                .iter_mut()
+                .find(|res| res.part == part && res.input_id == input_id)
+            {
+                res.metrics.insert(row.get(2), row.get::<i64, _>(3) as u64);
+            }
+        }
+        Ok(results)
+    }
+
     pub async fn fetch_inputs(
         &self,
+        year: i32,
         day: u8,
         limit: usize,
     ) -> Result<(Vec<i64>, Vec<Vec<u8>>), Error> {
-        let res = sqlx::query("SELECT id, data FROM inputs WHERE day = ? LIMIT ?")
+        let res = sqlx::query("SELECT id, data FROM inputs WHERE year = ? AND day = ? LIMIT ?")
+            .bind(year)
             .bind(day)
             .bind(limit as i64)
             .fetch_all(&self.0)
@@ -150,20 +765,27 @@ CREATE TABLE IF NOT EXISTS runs(
         Ok(res)
     }
 
-    pub async fn inputs_count(&self, day: u8) -> Result<usize, Error> {
-        let res = sqlx::query("SELECT COUNT(*) FROM inputs WHERE day = ?")
+    pub async fn inputs_count(&self, year: i32, day: u8) -> Result<usize, Error> {
+        let res = sqlx::query("SELECT COUNT(*) FROM inputs WHERE year = ? AND day = ?")
+            .bind(year)
             .bind(day)
             .fetch_one(&self.0)
             .await?;
         Ok(res.get::<i64, _>(0) as _)
     }
 
-    pub async fn solution_consensus(&self, input_id: i64) -> Result<Option<i64>, Error> {
-        let count: i64 = sqlx::query("SELECT COUNT(*) FROM solutions WHERE id = ?")
-            .bind(input_id)
-            .fetch_one(&self.0)
-            .await?
-            .get(0);
+    pub async fn solution_consensus(
+        &self,
+        input_id: i64,
+        part: u8,
+    ) -> Result<Option<String>, Error> {
+        let count: i64 =
+            sqlx::query("SELECT COUNT(*) FROM solutions WHERE input_id = ? AND part = ?")
+                .bind(input_id)
+                .bind(part)
+                .fetch_one(&self.0)
+                .await?
+                .get(0);
         if count < 3 {
             return Ok(None);
         }
@@ -171,8 +793,9 @@ CREATE TABLE IF NOT EXISTS runs(
         let majority = if majority >= 3 { majority } else { 3 };
 
         let res = sqlx::query(
-            "SELECT answer, COUNT(answer) FROM solutions WHERE id = ? GROUP BY answer ORDER BY COUNT(answer) DESC",
+            "SELECT answer, COUNT(answer) FROM solutions WHERE input_id = ? AND part = ? GROUP BY answer ORDER BY COUNT(answer) DESC",
         ).bind(input_id)
+        .bind(part)
         .fetch_optional(&self.0)
         .await?;
         if let Some(res) = res {
@@ -186,28 +809,47 @@ CREATE TABLE IF NOT EXISTS runs(
         Ok(None)
     }
 
-    pub async fn fetch_scores_for_day(&self, day: u8) -> Result<(Vec<Score>, Vec<Score>), Error> {
+    /// The best score of each submitter for both parts of the day, only counting runs in
+    /// the language if one is given
+    pub async fn fetch_scores_for_day(
+        &self,
+        year: i32,
+        day: u8,
+        ranking: Ranking,
+        language: Option<Language>,
+    ) -> Result<(Vec<Score>, Vec<Score>), Error> {
+        let scores = ranking.scores();
         // Greatest N Per Group? YAGNI, just run the query twice
-        let part1 = sqlx::query(
-            "SELECT submitter, score FROM runs 
-                WHERE day = ? AND part = 1
+        let part1 = sqlx::query(&format!(
+            "SELECT submitter, CAST(MIN(scores.score) AS REAL) AS best FROM ({scores}) AS scores
+                JOIN runs ON runs.id = scores.run_id
+                WHERE year = ? AND day = ? AND scores.part = 1
+                    AND scores.score IS NOT NULL
+                    AND (? IS NULL OR COALESCE(language, 'rust') = ?)
                 GROUP BY submitter
-                HAVING MIN(score) 
-                ORDER BY score ASC 
-                LIMIT 10",
-        )
+                ORDER BY best ASC
+                LIMIT 10"
+        ))
+        .bind(year)
         .bind(day)
+        .bind(language)
+        .bind(language)
         .fetch_all(&self.0)
         .await?;
-        let part2 = sqlx::query(
-            "SELECT submitter, score FROM runs 
-            WHERE day = ? AND part = 2
+        let part2 = sqlx::query(&format!(
+            "SELECT submitter, CAST(MIN(scores.score) AS REAL) AS best FROM ({scores}) AS scores
+            JOIN runs ON runs.id = scores.run_id
+            WHERE year = ? AND day = ? AND scores.part = 2
+                AND scores.score IS NOT NULL
+                AND (? IS NULL OR COALESCE(language, 'rust') = ?)
             GROUP BY submitter
-            HAVING MIN(score) 
-            ORDER BY score ASC 
-            LIMIT 10",
-        )
+            ORDER BY best ASC
+            LIMIT 10"
+        ))
+        .bind(year)
         .bind(day)
+        .bind(language)
+        .bind(language)
         .fetch_all(&self.0)
         .await?;
 
@@ -227,4 +869,422 @@ CREATE TABLE IF NOT EXISTS runs(
             .collect();
         Ok((part1, part2))
     }
+
+    /// The best scoring run for a part of the day, the earliest one on a tie
+    pub async fn fetch_best_run(
+        &self,
+        year: i32,
+        day: u8,
+        part: u8,
+        ranking: Ranking,
+        language: Option<Language>,
+    ) -> Result<Option<i64>, Error> {
+        let scores = ranking.scores();
+        let res = sqlx::query(&format!(
+            "SELECT runs.id FROM ({scores}) AS scores
+                JOIN runs ON runs.id = scores.run_id
+                WHERE year = ? AND day = ? AND scores.part = ?
+                    AND scores.score IS NOT NULL
+                    AND (? IS NULL OR COALESCE(language, 'rust') = ?)
+                ORDER BY scores.score ASC, runs.id ASC
+                LIMIT 1"
+        ))
+        .bind(year)
+        .bind(day)
+        .bind(part)
+        .bind(language)
+        .bind(language)
+        .fetch_optional(&self.0)
+        .await?;
+        Ok(res.map(|row| row.get(0)))
+    }
+
+    /// How runs for the day are benchmarked, by instructions unless an admin said otherwise
+    pub async fn fetch_day_mode(&self, year: i32, day: u8) -> Result<Mode, Error> {
+        let res = sqlx::query("SELECT mode FROM days WHERE year = ? AND day = ?")
+            .bind(year)
+            .bind(day)
+            .fetch_optional(&self.0)
+            .await?;
+        Ok(res.map(|row| row.get(0)).unwrap_or_default())
+    }
+
+    pub async fn set_day_mode(&self, year: i32, day: u8, mode: Mode) -> Result<(), Error> {
+        sqlx::query("INSERT OR REPLACE INTO days (year, day, mode) VALUES (?, ?, ?)")
+            .bind(year)
+            .bind(day)
+            .bind(mode)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_job(&self, run_id: i64) -> Result<i64, Error> {
+        let res = sqlx::query("INSERT INTO jobs (run_id, state) VALUES (?, ?)")
+            .bind(run_id)
+            .bind(JobState::Pending)
+            .execute(&self.0)
+            .await?;
+        Ok(res.last_insert_rowid())
+    }
+
+    pub async fn fetch_job(&self, id: i64) -> Result<Job, Error> {
+        let row = sqlx::query("SELECT run_id, attempts FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.0)
+            .await?;
+        Ok(Job {
+            run_id: row.get(0),
+            attempts: row.get(1),
+        })
+    }
+
+    /// Takes the lease on a job if it is pending or its previous lease has expired, counting
+    /// an attempt for the latter. Returns whether the lease was acquired.
+    pub async fn claim_job(&self, id: i64, now: i64, lease_expires: i64) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "UPDATE jobs SET state = ?, lease_expires = ?,
+                    attempts = attempts + CASE WHEN state = ? THEN 1 ELSE 0 END
+                WHERE id = ? AND (state = ? OR (state = ? AND lease_expires < ?))",
+        )
+        .bind(JobState::Running)
+        .bind(lease_expires)
+        .bind(JobState::Running)
+        .bind(id)
+        .bind(JobState::Pending)
+        .bind(JobState::Running)
+        .bind(now)
+        .execute(&self.0)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    pub async fn renew_lease(&self, id: i64, lease_expires: i64) -> Result<(), Error> {
+        sqlx::query("UPDATE jobs SET lease_expires = ? WHERE id = ? AND state = ?")
+            .bind(lease_expires)
+            .bind(id)
+            .bind(JobState::Running)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns every running job to the queue, counting an attempt as they may be why the
+    /// bot stopped. Only valid when no job can be in progress, such as on startup.
+    pub async fn release_jobs(&self) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE jobs SET state = ?, attempts = attempts + 1, lease_expires = NULL
+                WHERE state = ?",
+        )
+        .bind(JobState::Pending)
+        .bind(JobState::Running)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns a job that failed to the queue, counting an attempt
+    pub async fn retry_job(&self, id: i64) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE jobs SET state = ?, attempts = attempts + 1, lease_expires = NULL
+                WHERE id = ?",
+        )
+        .bind(JobState::Pending)
+        .bind(id)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// Jobs that are pending or whose lease has expired
+    pub async fn fetch_claimable_jobs(&self, now: i64) -> Result<Vec<i64>, Error> {
+        let res = sqlx::query(
+            "SELECT id FROM jobs WHERE state = ? OR (state = ? AND lease_expires < ?) ORDER BY id",
+        )
+        .bind(JobState::Pending)
+        .bind(JobState::Running)
+        .bind(now)
+        .fetch_all(&self.0)
+        .await?;
+        Ok(res.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn finish_job(&self, id: i64, state: JobState) -> Result<(), Error> {
+        sqlx::query("UPDATE jobs SET state = ?, lease_expires = NULL WHERE id = ?")
+            .bind(state)
+            .bind(id)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A column as `PRAGMA table_info` describes it
+struct Column {
+    name: String,
+    ty: String,
+    default: Option<String>,
+}
+
+/// The columns of `table` in `schema`, none if it doesn't exist
+async fn columns(
+    conn: &mut SqliteConnection,
+    schema: &str,
+    table: &str,
+) -> Result<Vec<Column>, Error> {
+    let rows = sqlx::query(&format!("PRAGMA {schema}.table_info({table})"))
+        .fetch_all(&mut *conn)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(Column {
+                name: row.try_get("name")?,
+                ty: row.try_get("type")?,
+                default: row.try_get("dflt_value")?,
+            })
+        })
+        .collect()
+}
+
+/// Adds the columns `create` has that an existing table made by an older version of it lacks
+async fn add_missing_columns(conn: &mut SqliteConnection, create: &str) -> Result<(), Error> {
+    let (_, rest) = create
+        .split_once("EXISTS ")
+        .expect("a CREATE TABLE IF NOT EXISTS");
+    let (table, _) = rest.split_once('(').expect("a column list");
+    let existing = columns(conn, "main", table).await?;
+    if existing.is_empty() {
+        return Ok(());
+    }
+
+    // The easiest way to see the columns as they are now
+    let latest = create.replacen(
+        &format!("IF NOT EXISTS {table}("),
+        &format!("temp.{table}_latest("),
+        1,
+    );
+    sqlx::query(&latest).execute(&mut *conn).await?;
+    for column in columns(conn, "temp", &format!("{table}_latest")).await? {
+        if existing.iter().any(|c| c.name == column.name) {
+            continue;
+        }
+        let default = column
+            .default
+            .map(|default| format!(" DEFAULT {default}"))
+            .unwrap_or_default();
+        sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {} {}{default}",
+            column.name, column.ty
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+    sqlx::query(&format!("DROP TABLE temp.{table}_latest"))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Brings the tables of a version of the bot from before schema versions were kept up to
+/// date. Those only ever gained columns, apart from the changes made here.
+async fn upgrade_unversioned(conn: &mut SqliteConnection, legacy_year: i32) -> Result<(), Error> {
+    let inputs_had_year = columns(conn, "main", "inputs")
+        .await?
+        .iter()
+        .any(|c| c.name == "year");
+    let old_runs = columns(conn, "main", "runs").await?;
+    let answer_type = columns(conn, "main", "solutions")
+        .await?
+        .into_iter()
+        .find(|c| c.name == "answer")
+        .map(|c| c.ty);
+
+    for table in TABLES {
+        add_missing_columns(conn, table).await?;
+        sqlx::query(table).execute(&mut *conn).await?;
+    }
+
+    // Inputs were stored with the submitter as the day and the other way around until years
+    // were kept. Assignments all read the old values, so this swaps them.
+    if !inputs_had_year {
+        sqlx::query("UPDATE inputs SET day = submitter, submitter = day WHERE day > 25")
+            .execute(&mut *conn)
+            .await?;
+    }
+    sqlx::query("UPDATE inputs SET year = ? WHERE year IS NULL")
+        .bind(legacy_year)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("UPDATE runs SET year = ? WHERE year IS NULL")
+        .bind(legacy_year)
+        .execute(&mut *conn)
+        .await?;
+    // Only Rust was supported before languages were kept
+    sqlx::query("UPDATE runs SET language = 'rust' WHERE language IS NULL")
+        .execute(&mut *conn)
+        .await?;
+
+    // Answers were integers until they could be anything. A column can't change its type, so
+    // the table is made again.
+    if answer_type.is_some_and(|ty| ty != "TEXT") {
+        sqlx::query("ALTER TABLE solutions RENAME TO old_solutions")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(TABLES[1]).execute(&mut *conn).await?;
+        sqlx::query(
+            "INSERT INTO solutions (id, input_id, part, submitter, answer)
+                SELECT id, input_id, part, submitter, CAST(answer AS TEXT) FROM old_solutions",
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query("DROP TABLE old_solutions")
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    // Runs were scored as a whole until each part got a score of its own
+    let had = |name: &str| old_runs.iter().any(|c| c.name == name);
+    if had("score") {
+        let split = ["parse_score", "solve_score"]
+            .into_iter()
+            .filter(|&name| had(name))
+            .collect::<Vec<_>>();
+        let columns = ["run_id", "part", "score"]
+            .into_iter()
+            .chain(split.iter().copied())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let values = ["id", "part", "score"]
+            .into_iter()
+            .chain(split.iter().copied())
+            .collect::<Vec<_>>()
+            .join(", ");
+        sqlx::query(&format!(
+            "INSERT OR IGNORE INTO run_scores ({columns})
+                SELECT {values} FROM runs WHERE score IS NOT NULL"
+        ))
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query("UPDATE runs SET status = 'scored' WHERE status IS NULL AND score IS NOT NULL")
+            .execute(&mut *conn)
+            .await?;
+        for column in ["score"].into_iter().chain(split) {
+            sqlx::query(&format!("ALTER TABLE runs DROP COLUMN {column}"))
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    // Anything else from before statuses were kept never got a score
+    sqlx::query(
+        "UPDATE runs SET status = 'failed', error = 'Not scored before the bot was updated'
+            WHERE status IS NULL",
+    )
+    .execute(&mut *conn)
+    .await?;
+    // Results were only kept for a part each run had to itself before parts had results
+    sqlx::query(
+        "UPDATE run_results SET part = (SELECT part FROM runs WHERE runs.id = run_results.run_id)
+            WHERE part IS NULL",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// An empty database in memory
+    pub(crate) async fn memory() -> SqlitePool {
+        // Every connection to memory has a database of its own
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn databases_from_before_versions_are_upgraded() {
+        let pool = memory().await;
+        // The tables as the first version of the bot made them, with its data
+        for statement in [
+            "CREATE TABLE inputs(id INTEGER PRIMARY KEY, day INTEGER, submitter INTEGER, data BLOB)",
+            "CREATE TABLE solutions(id INTEGER PRIMARY KEY, input_id INTEGER, part INTEGER,
+                submitter INTEGER, answer INTEGER)",
+            "CREATE TABLE runs(id INTEGER PRIMARY KEY, submitter INTEGER, day INTEGER,
+                part INTEGER, score NUMERIC, code BLOB)",
+            "INSERT INTO inputs (id, day, submitter, data) VALUES (1, 1234567890, 5, 'input')",
+            "INSERT INTO solutions (input_id, part, submitter, answer)
+                VALUES (1, 1, 1, 42), (1, 1, 2, 42), (1, 1, 3, 42), (1, 1, 4, 42)",
+            "INSERT INTO runs (id, submitter, day, part, score, code)
+                VALUES (1, 1234567890, 5, 1, 1000, 'fast'), (2, 1234567890, 5, 1, NULL, 'lost')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        Database::migrate(&pool, 2022).await.unwrap();
+        let database = Database(pool);
+
+        let (ids, inputs) = database.fetch_inputs(2022, 5, 3).await.unwrap();
+        assert_eq!((ids, inputs), (vec![1], vec![b"input".to_vec()]));
+        assert_eq!(
+            database.solution_consensus(1, 1).await.unwrap().as_deref(),
+            Some("42")
+        );
+        let run = database.fetch_run(1).await.unwrap();
+        assert_eq!((run.year, run.language), (2022, Language::Rust));
+        let (part1, _) = database
+            .fetch_scores_for_day(2022, 5, Ranking::Instructions(Phase::Total), None)
+            .await
+            .unwrap();
+        assert_eq!(part1.len(), 1);
+        assert_eq!(part1[0].score, 1000.0);
+        let statuses: Vec<String> = sqlx::query_scalar("SELECT status FROM runs ORDER BY id")
+            .fetch_all(&database.0)
+            .await
+            .unwrap();
+        assert_eq!(statuses, ["scored", "failed"]);
+
+        // Done once and for all
+        Database::migrate(&database.0, 2023).await.unwrap();
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&database.0)
+            .await
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(database.fetch_run(1).await.unwrap().year, 2022);
+    }
+
+    #[tokio::test]
+    async fn claims_that_never_finish_count_as_attempts() {
+        let database = Database::in_memory().await.unwrap();
+        let run = database
+            .insert_run(UserId::new(1), 2024, 1, Some(1), b"", Language::Rust, None)
+            .await
+            .unwrap();
+        let job = database.insert_job(run).await.unwrap();
+
+        assert!(database.claim_job(job, 0, 60).await.unwrap());
+        assert_eq!(database.fetch_job(job).await.unwrap().attempts, 0);
+
+        // Not claimable again until the lease expires
+        assert!(!database.claim_job(job, 30, 90).await.unwrap());
+        assert!(database.claim_job(job, 61, 121).await.unwrap());
+        assert_eq!(database.fetch_job(job).await.unwrap().attempts, 1);
+
+        // Left behind by the bot stopping
+        database.release_jobs().await.unwrap();
+        assert!(database.claim_job(job, 0, 60).await.unwrap());
+        assert_eq!(database.fetch_job(job).await.unwrap().attempts, 2);
+
+        // Failed on our end
+        database.retry_job(job).await.unwrap();
+        assert!(database.claim_job(job, 0, 60).await.unwrap());
+        assert_eq!(database.fetch_job(job).await.unwrap().attempts, 3);
+    }
 }
@@ -0,0 +1,117 @@
+//! Sizing up code for the code golf leaderboard
+
+use std::{iter::Peekable, str::FromStr};
+
+use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree, token_stream::IntoIter};
+
+/// Counts the tokens the code lexes into, which leaves out whitespace and comments. Returns
+/// `None` if the code isn't valid UTF-8 or can't be lexed as Rust.
+pub fn count_tokens(code: &[u8]) -> Option<u64> {
+    let code = std::str::from_utf8(code).ok()?;
+    let tokens = TokenStream::from_str(code).ok()?;
+    Some(count(tokens))
+}
+
+fn count(tokens: TokenStream) -> u64 {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut total = 0;
+    while let Some(token) = tokens.next() {
+        total += match token {
+            TokenTree::Group(group) => {
+                let delimiters = match group.delimiter() {
+                    Delimiter::None => 0,
+                    _ => 2,
+                };
+                delimiters + count(group.stream())
+            }
+            TokenTree::Punct(punct) => {
+                // Doc comments are lexed into `#[doc = "..."]` attributes
+                if punct.as_char() == '#' && skip_doc_comment(&mut tokens) {
+                    continue;
+                }
+                // Operators like `::` and lifetimes like `'a` are lexed one character at a
+                // time, only the last one counts
+                match (punct.spacing(), tokens.peek()) {
+                    (Spacing::Joint, Some(TokenTree::Punct(next))) if next.as_char() != '\'' => 0,
+                    (Spacing::Joint, Some(TokenTree::Ident(_))) if punct.as_char() == '\'' => 0,
+                    _ => 1,
+                }
+            }
+            TokenTree::Ident(_) | TokenTree::Literal(_) => 1,
+        };
+    }
+    total
+}
+
+/// Skips the rest of a doc comment following a `#`, returning whether there was one
+fn skip_doc_comment(tokens: &mut Peekable<IntoIter>) -> bool {
+    let is_doc = |token: Option<&TokenTree>| match token {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => matches!(
+            group.stream().into_iter().next(),
+            Some(TokenTree::Ident(ident)) if ident == "doc"
+        ),
+        _ => false,
+    };
+
+    let inner = matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+    if inner {
+        // Can't peek two ahead, so look at a copy
+        let mut ahead = tokens.clone();
+        ahead.next();
+        if !is_doc(ahead.peek()) {
+            return false;
+        }
+        tokens.next();
+    } else if !is_doc(tokens.peek()) {
+        return false;
+    }
+    tokens.next();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(code: &str) -> u64 {
+        count_tokens(code.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn operators_count_once() {
+        assert_eq!(tokens("a::b"), 3);
+        assert_eq!(tokens("x += 1"), 3);
+        assert_eq!(tokens("a..=b"), 3);
+        assert_eq!(tokens("fn f() -> u8 {}"), 8);
+    }
+
+    #[test]
+    fn lifetimes_count_once() {
+        assert_eq!(tokens("&'a str"), 3);
+        assert_eq!(tokens("'a: loop {}"), 5);
+        assert_eq!(tokens("'x'"), 1);
+    }
+
+    #[test]
+    fn doc_comments_are_skipped() {
+        assert_eq!(tokens("/// Docs\nfn f() {}"), 6);
+        assert_eq!(tokens("//! Docs\nfn f() {}"), 6);
+        assert_eq!(tokens("/** Docs */ fn f() {}"), 6);
+        // Other attributes aren't
+        assert_eq!(tokens("#[inline] fn f() {}"), 10);
+        assert_eq!(tokens("#![allow(unused)]"), 7);
+    }
+
+    #[test]
+    fn delimiters_count_as_a_pair() {
+        assert_eq!(tokens("(a, [b])"), 7);
+        assert_eq!(tokens("{}"), 2);
+    }
+
+    #[test]
+    fn code_that_isnt_rust_is_not_counted() {
+        assert_eq!(count_tokens(b"int main() { puts('hello'); }"), None);
+        assert_eq!(count_tokens(b"fn main() {"), None);
+        assert_eq!(count_tokens(&[0xff, 0xfe]), None);
+    }
+}
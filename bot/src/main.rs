@@ -1,25 +1,50 @@
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 
 use database::Database;
 use poise::serenity_prelude::{self as serenity};
+use pool::WorkerPool;
 
 use tokio::{
     signal::unix::{SignalKind, signal},
     sync::watch,
 };
+use worker::{Endpoint, Limits, WallClock, auth::Key};
 
 mod commands;
 mod database;
+mod golf;
+mod pool;
+mod queue;
 mod runner;
+mod sandbox;
 mod utils;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Arc<Data>, Error>;
 
+const DEFAULT_MAX_CONCURRENT_RUNS: usize = 1;
+const DEFAULT_MAX_JOBS_PER_WORKER: usize = 20;
+const DEFAULT_COMPILE_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_RUN_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_WALL_CLOCK_WARMUP: Duration = Duration::from_secs(1);
+const DEFAULT_WALL_CLOCK_SAMPLES: u32 = 100;
+
 pub struct Data {
     database: Database,
-    input_watch: watch::Sender<u8>,
-    consensus_watch: watch::Sender<i64>,
+    /// Notified whenever inputs are added. Waiters check the database again for whatever
+    /// they wait for, so that no notification can be missed for another.
+    input_watch: watch::Sender<()>,
+    /// Notified whenever answers are added, which may make a consensus
+    consensus_watch: watch::Sender<()>,
+    /// Warm worker containers or connections to remote workers, at most one per
+    /// concurrent run
+    workers: WorkerPool,
+    /// What every submission is allowed to spend
+    limits: Limits,
+    /// Passed on to callgrind, such as `--cache-sim=yes` for the cache metrics
+    callgrind_args: Vec<String>,
+    /// How runs are timed on days benchmarked by wall clock
+    wall_clock: WallClock,
 }
 
 #[poise::command(slash_command)]
@@ -34,6 +59,67 @@ async fn main() {
     let token = env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
 
     let db_path = env::var("DATABASE_PATH").unwrap_or_else(|_| database::DEFAULT_PATH.into());
+    // What year inputs and runs stored before years were kept are from
+    let legacy_year = env::var("LEGACY_YEAR")
+        .map(|n| n.parse().expect("invalid LEGACY_YEAR"))
+        .unwrap_or_else(|_| utils::aoc_today().0);
+
+    let max_runners = env::var("MAX_CONCURRENT_RUNS")
+        .map(|n| n.parse().expect("invalid MAX_CONCURRENT_RUNS"))
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_RUNS);
+    let max_jobs = env::var("MAX_JOBS_PER_WORKER")
+        .map(|n| n.parse().expect("invalid MAX_JOBS_PER_WORKER"))
+        .unwrap_or(DEFAULT_MAX_JOBS_PER_WORKER);
+
+    let limits = Limits {
+        compile_timeout: env::var("COMPILE_TIMEOUT_SECS")
+            .map(|n| Duration::from_secs(n.parse().expect("invalid COMPILE_TIMEOUT_SECS")))
+            .unwrap_or(DEFAULT_COMPILE_TIMEOUT),
+        run_timeout: env::var("RUN_TIMEOUT_SECS")
+            .map(|n| Duration::from_secs(n.parse().expect("invalid RUN_TIMEOUT_SECS")))
+            .unwrap_or(DEFAULT_RUN_TIMEOUT),
+        max_instructions: env::var("MAX_INSTRUCTIONS")
+            .map(|n| n.parse().expect("invalid MAX_INSTRUCTIONS"))
+            .ok(),
+    };
+
+    let callgrind_args = env::var("CALLGRIND_ARGS")
+        .map(|args| args.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default();
+
+    let wall_clock = WallClock {
+        warmup: env::var("WALL_CLOCK_WARMUP_MS")
+            .map(|n| Duration::from_millis(n.parse().expect("invalid WALL_CLOCK_WARMUP_MS")))
+            .unwrap_or(DEFAULT_WALL_CLOCK_WARMUP),
+        samples: env::var("WALL_CLOCK_SAMPLES")
+            .map(|n| n.parse().expect("invalid WALL_CLOCK_SAMPLES"))
+            .unwrap_or(DEFAULT_WALL_CLOCK_SAMPLES),
+    };
+
+    // Remote workers replace the sandbox. Workers running several jobs at once are listed
+    // once for each job, and get them all over a single connection.
+    let workers = match env::var("WORKER_ENDPOINTS") {
+        Ok(endpoints) => {
+            let endpoints = endpoints
+                .split(',')
+                .map(|endpoint| endpoint.trim().parse::<Endpoint>())
+                .collect::<Result<Vec<_>, _>>()
+                .expect("invalid WORKER_ENDPOINTS");
+            let key = env::var("WORKER_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| Key::new(secret.as_bytes()));
+            let tcp = endpoints.iter().any(|e| matches!(e, Endpoint::Tcp(_)));
+            if tcp && key.is_none() {
+                panic!("WORKER_SECRET must be set for workers reached over TCP");
+            }
+            WorkerPool::remote(endpoints, key, max_jobs)
+        }
+        Err(_) => {
+            let backend = sandbox::from_env().expect("invalid sandbox configuration");
+            WorkerPool::new(backend, max_runners, max_jobs)
+        }
+    };
 
     let commands = vec![commands::aoc(), commands::input()];
 
@@ -43,9 +129,9 @@ async fn main() {
     };
 
     let framework = poise::Framework::builder()
-        .setup(move |_ctx, _ready, framework| {
+        .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
-                let database = Database::init(&db_path).await?;
+                let database = Database::init(&db_path, legacy_year).await?;
 
                 let shard_manager = framework.shard_manager().clone();
                 tokio::spawn(async move {
@@ -57,14 +143,28 @@ async fn main() {
                     shard_manager.shutdown_all().await;
                 });
 
-                let (input_watch, _) = watch::channel(0);
-                let (consensus_watch, _) = watch::channel(0);
+                let (input_watch, _) = watch::channel(());
+                let (consensus_watch, _) = watch::channel(());
 
-                Ok(Arc::new(Data {
+                let data = Arc::new(Data {
                     database,
                     input_watch,
                     consensus_watch,
-                }))
+                    workers,
+                    limits,
+                    callgrind_args,
+                    wall_clock,
+                });
+
+                let http = Arc::clone(&ctx.http);
+                let queue_data = Arc::clone(&data);
+                tokio::spawn(async move {
+                    if let Err(e) = queue::recover(http, queue_data).await {
+                        log::error!("Job recovery stopped: {e}");
+                    }
+                });
+
+                Ok(data)
             })
         })
         .options(options)
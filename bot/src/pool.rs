@@ -0,0 +1,705 @@
+//! A pool of long lived workers that are reused between submissions.
+//!
+//! Keeping containers around skips their startup and keeps the runner crate's
+//! dependencies built. Anything a submission leaves behind in a container can affect the
+//! submissions after it though, so containers are recycled after a number of jobs and
+//! whenever something goes wrong in one. Workers that run submissions as themselves
+//! rather than as users of their own are recycled after every job.
+//!
+//! Workers can also run elsewhere and be reached over a socket, in which case they are
+//! managed by whoever started them. The pool then only recycles its connections to them,
+//! each of which carries as many jobs at once as its worker was listed for.
+
+use std::{
+    collections::HashMap,
+    process::{self, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
+    net::{TcpStream, UnixStream},
+    process::Child,
+    select,
+    sync::{Semaphore, SemaphorePermit, mpsc, oneshot},
+    task,
+    time::{Instant, sleep_until, timeout},
+};
+use tokio_util::io::SyncIoBridge;
+use worker::{
+    Capability, Endpoint, Greeting, Hello, Message, Outcome, Progress, Request, Response, Signed,
+    auth::{self, Key, Session},
+    codec::{PROTOCOL_VERSION, read_frame, write_frame},
+};
+
+use crate::{Error, sandbox::SandboxBackend};
+
+/// How long a retired worker gets to exit on its own before it is killed
+const RETIRE_GRACE: Duration = Duration::from_secs(10);
+/// How long a new worker has to introduce itself
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long past the limits of a request its response may take, for setting up and
+/// reading results
+const RESPONSE_GRACE: Duration = Duration::from_secs(30);
+
+/// Used to give every worker a unique name
+static NEXT_WORKER: AtomicU64 = AtomicU64::new(0);
+
+/// Requests sent to a worker that are waiting for their response, by `Request::id`
+type Pending = Arc<Mutex<HashMap<i64, PendingRequest>>>;
+
+struct PendingRequest {
+    response: oneshot::Sender<Response>,
+    progress: mpsc::UnboundedSender<Progress>,
+}
+
+pub struct WorkerPool {
+    source: Source,
+    /// Every worker the pool started or connected to, busy or not
+    workers: Mutex<Vec<Worker>>,
+    /// Limits how many jobs may run at once
+    permits: Semaphore,
+    /// Held while connecting to a remote worker, so that two jobs never both connect to
+    /// the room left on the same endpoint
+    connecting: tokio::sync::Mutex<()>,
+    /// Workers are recycled after handling this many requests
+    max_jobs: usize,
+}
+
+/// Where the pool gets its workers from
+enum Source {
+    /// Started on demand in a sandbox on this host
+    Sandbox(Arc<dyn SandboxBackend>),
+    /// Already running elsewhere
+    Remote {
+        /// Every endpoint along with how many jobs it may run at once, which is how often
+        /// it was listed. Together they have room for every permit.
+        endpoints: Vec<(Endpoint, usize)>,
+        key: Option<Key>,
+    },
+}
+
+/// A job's share of a worker, which is given back to the pool when dropped
+pub struct PooledWorker<'a> {
+    pool: &'a WorkerPool,
+    channel: Channel,
+    _permit: SemaphorePermit<'a>,
+}
+
+/// What it takes to send requests to a worker, shared by every job it runs
+#[derive(Clone)]
+struct Channel {
+    name: String,
+    requests: mpsc::UnboundedSender<Signed<Request>>,
+    pending: Pending,
+    /// Set once nothing more is read from the worker
+    closed: Arc<AtomicBool>,
+    /// What the worker said it supports when it started
+    capabilities: Vec<Capability>,
+    /// Authenticates requests and messages, for workers that need it
+    key: Option<Key>,
+    /// The challenges both sides sent when the worker started, which every MAC covers
+    session: Session,
+}
+
+struct Worker {
+    connection: Connection,
+    channel: Channel,
+    /// How many jobs it may run at once
+    capacity: usize,
+    /// How many jobs it is running
+    busy: usize,
+    jobs: usize,
+    /// Takes no more jobs, and is retired once those it runs are done
+    failed: bool,
+}
+
+enum Connection {
+    /// A process started by a sandbox backend, speaking over its stdio
+    Process {
+        backend: Arc<dyn SandboxBackend>,
+        child: Child,
+    },
+    /// A worker someone else started, speaking over a socket
+    Socket(Endpoint),
+}
+
+impl WorkerPool {
+    pub fn new(backend: Box<dyn SandboxBackend>, size: usize, max_jobs: usize) -> Self {
+        Self {
+            source: Source::Sandbox(backend.into()),
+            workers: Mutex::new(Vec::new()),
+            connecting: tokio::sync::Mutex::new(()),
+            permits: Semaphore::new(size),
+            max_jobs,
+        }
+    }
+
+    /// A pool of workers listening on `endpoints`, each running as many jobs at once as it
+    /// is listed for over a single connection. Workers with a secret only accept requests
+    /// signed with `key`.
+    pub fn remote(listed: Vec<Endpoint>, key: Option<Key>, max_jobs: usize) -> Self {
+        let mut endpoints: Vec<(Endpoint, usize)> = Vec::new();
+        for endpoint in listed {
+            match endpoints.iter_mut().find(|(e, _)| *e == endpoint) {
+                Some((_, room)) => *room += 1,
+                None => endpoints.push((endpoint, 1)),
+            }
+        }
+        Self {
+            permits: Semaphore::new(endpoints.iter().map(|(_, room)| room).sum()),
+            source: Source::Remote { endpoints, key },
+            workers: Mutex::new(Vec::new()),
+            connecting: tokio::sync::Mutex::new(()),
+            max_jobs,
+        }
+    }
+
+    /// Waits for room for a job, then hands out a share of a healthy worker with room for
+    /// it or starts a new one
+    pub async fn get(&self) -> Result<PooledWorker<'_>, Error> {
+        let permit = self.permits.acquire().await?;
+        let channel = match self.join() {
+            Some(channel) => channel,
+            None => match &self.source {
+                Source::Sandbox(backend) => self.add(Worker::spawn(backend).await?),
+                Source::Remote { endpoints, key } => {
+                    let _connecting = self.connecting.lock().await;
+                    // Whoever connected in the meantime may have left room
+                    match self.join() {
+                        Some(channel) => channel,
+                        None => {
+                            let (endpoint, room) = self
+                                .room(endpoints)
+                                .expect("an endpoint with room for every permit");
+                            self.add(Worker::connect(&endpoint, key.clone(), room).await?)
+                        }
+                    }
+                }
+            },
+        };
+        Ok(PooledWorker {
+            pool: self,
+            channel,
+            _permit: permit,
+        })
+    }
+
+    /// Takes up room on a worker that has some, retiring the idle workers that died
+    fn join(&self) -> Option<Channel> {
+        let (channel, dead) = {
+            let mut workers = self.workers.lock().unwrap();
+            let dead: Vec<_> = workers
+                .extract_if(.., |worker| worker.busy == 0 && !worker.is_healthy())
+                .collect();
+            let channel = workers.iter_mut().find_map(|worker| {
+                (worker.busy < worker.capacity && !self.used_up(worker)).then(|| {
+                    worker.busy += 1;
+                    worker.jobs += 1;
+                    worker.channel.clone()
+                })
+            });
+            (channel, dead)
+        };
+        for worker in dead {
+            log::warn!("Worker {} died while idle", worker.channel.name);
+            worker.retire();
+        }
+        channel
+    }
+
+    /// Adds a new worker to the pool with a job taking up room on it
+    fn add(&self, mut worker: Worker) -> Channel {
+        worker.busy = 1;
+        worker.jobs = 1;
+        let channel = worker.channel.clone();
+        self.workers.lock().unwrap().push(worker);
+        channel
+    }
+
+    /// An endpoint with room for another connection, along with how many jobs fit. The
+    /// jobs still running on connections that take no more count against it.
+    fn room(&self, endpoints: &[(Endpoint, usize)]) -> Option<(Endpoint, usize)> {
+        let mut workers = self.workers.lock().unwrap();
+        endpoints.iter().find_map(|(endpoint, listed)| {
+            let taken: usize = workers
+                .iter_mut()
+                .filter(
+                    |worker| matches!(&worker.connection, Connection::Socket(e) if e == endpoint),
+                )
+                .map(|worker| match self.used_up(worker) {
+                    true => worker.busy,
+                    false => worker.capacity,
+                })
+                .sum();
+            (taken < *listed).then(|| (endpoint.clone(), listed - taken))
+        })
+    }
+
+    /// Whether a worker takes no more jobs
+    fn used_up(&self, worker: &mut Worker) -> bool {
+        // A submission could have left something behind for the next one to run into
+        let shared = matches!(self.source, Source::Sandbox(_))
+            && !worker.channel.capabilities.contains(&Capability::Isolation);
+        worker.failed
+            || (shared && worker.jobs > 0)
+            || worker.jobs >= self.max_jobs
+            || !worker.is_healthy()
+    }
+
+    /// Keeps the worker of `channel` from taking more jobs
+    fn fail(&self, channel: &Channel) {
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(worker) = workers.iter_mut().find(|worker| worker.runs(channel)) {
+            worker.failed = true;
+        }
+    }
+
+    /// Gives back a job's share of the worker of `channel`, retiring the worker once it
+    /// runs no jobs and takes no more
+    fn put(&self, channel: &Channel) {
+        let retired = {
+            let mut workers = self.workers.lock().unwrap();
+            let Some(i) = workers.iter().position(|worker| worker.runs(channel)) else {
+                return;
+            };
+            workers[i].busy -= 1;
+            let done = workers[i].busy == 0 && self.used_up(&mut workers[i]);
+            done.then(|| workers.swap_remove(i))
+        };
+        if let Some(worker) = retired {
+            worker.retire();
+        }
+    }
+}
+
+impl PooledWorker<'_> {
+    /// Sends a request to the worker and waits for its response, passing on progress
+    /// along the way. A worker that ignores the limits of the request takes no more jobs,
+    /// and is killed once the others it runs are done.
+    pub async fn run(
+        &self,
+        req: Request,
+        progress: mpsc::UnboundedSender<Progress>,
+    ) -> Result<Response, Error> {
+        let channel = &self.channel;
+        if let Some(missing) = req
+            .required_capabilities()
+            .into_iter()
+            .find(|cap| !channel.capabilities.contains(cap))
+        {
+            return Err(format!("Worker {} does not support {missing:?}", channel.name).into());
+        }
+        let id = req.id;
+        // Counted from when the worker starts compiling, as the request may first wait for
+        // a slot on the worker. That wait can take as long again at most.
+        let limit = req.limits.compile_timeout + req.limits.run_timeout + RESPONSE_GRACE;
+        let mut deadline = Instant::now() + limit;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let res = {
+            let response = channel.send(req, tx);
+            tokio::pin!(response);
+            loop {
+                select! {
+                    res = &mut response => break Some(res),
+                    Some(update) = rx.recv() => {
+                        if update == Progress::Compiling {
+                            deadline = Instant::now() + limit;
+                        }
+                        let _ = progress.send(update);
+                    }
+                    () = sleep_until(deadline) => break None,
+                }
+            }
+        };
+        // Whatever arrived just before the response is still passed on
+        while let Ok(update) = rx.try_recv() {
+            let _ = progress.send(update);
+        }
+        let res = res.unwrap_or_else(|| {
+            log::warn!("Worker {} did not respond to {id} in time", channel.name);
+            Ok(Response {
+                id,
+                outcome: Outcome::Timeout,
+            })
+        });
+        // The worker itself may have been affected, so it isn't trusted with another request
+        if matches!(
+            res,
+            Err(_)
+                | Ok(Response {
+                    outcome: Outcome::InfrastructureError(_)
+                        | Outcome::MemoryLimit
+                        | Outcome::CompileTimeout
+                        | Outcome::RunTimeout
+                        | Outcome::Timeout,
+                    ..
+                })
+        ) {
+            self.pool.fail(channel);
+        }
+        res
+    }
+}
+
+impl Drop for PooledWorker<'_> {
+    fn drop(&mut self) {
+        self.pool.put(&self.channel);
+    }
+}
+
+impl Worker {
+    async fn spawn(backend: &Arc<dyn SandboxBackend>) -> Result<Self, Error> {
+        let name = format!(
+            "runner-{}-{}",
+            process::id(),
+            NEXT_WORKER.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut child = backend
+            .command(&name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        log::info!("Started worker {name}");
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+
+        let stderr_name = name.clone();
+        tokio::spawn(async move {
+            let mut lines = stderr.lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                log::debug!("Worker {stderr_name}: {line}");
+            }
+        });
+
+        let connection = Connection::Process {
+            backend: Arc::clone(backend),
+            child,
+        };
+        Self::start(name, connection, stdout, stdin, None, 1).await
+    }
+
+    /// Connects to the worker at `endpoint` to run up to `capacity` jobs at once
+    async fn connect(
+        endpoint: &Endpoint,
+        key: Option<Key>,
+        capacity: usize,
+    ) -> Result<Self, Error> {
+        let name = endpoint.to_string();
+        let connection = Connection::Socket(endpoint.clone());
+        let timed_out = |_| format!("Timed out connecting to worker {name}");
+        match endpoint {
+            Endpoint::Tcp(addr) => {
+                let stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(addr))
+                    .await
+                    .map_err(timed_out)??;
+                stream.set_nodelay(true)?;
+                let (output, input) = stream.into_split();
+                Self::start(name, connection, output, input, key, capacity).await
+            }
+            Endpoint::Unix(path) => {
+                let stream = timeout(HANDSHAKE_TIMEOUT, UnixStream::connect(path))
+                    .await
+                    .map_err(timed_out)??;
+                let (output, input) = stream.into_split();
+                Self::start(name, connection, output, input, key, capacity).await
+            }
+        }
+    }
+
+    /// Sets up talking to a worker over its output and input, then waits for it to
+    /// introduce itself
+    async fn start(
+        name: String,
+        connection: Connection,
+        output: impl AsyncRead + Send + Unpin + 'static,
+        input: impl AsyncWrite + Send + Unpin + 'static,
+        key: Option<Key>,
+        capacity: usize,
+    ) -> Result<Self, Error> {
+        let (requests, mut rx) = mpsc::unbounded_channel::<Signed<Request>>();
+        let writer_name = name.clone();
+        let challenge = auth::challenge();
+        task::spawn_blocking(move || {
+            let mut input = SyncIoBridge::new(input);
+            if let Err(e) = write_frame(&mut input, &Greeting { challenge }) {
+                log::warn!("Failed to greet worker {writer_name}: {e}");
+                return;
+            }
+            while let Some(req) = rx.blocking_recv() {
+                if let Err(e) = write_frame(&mut input, &req) {
+                    log::warn!("Failed to send request to worker {writer_name}: {e}");
+                    break;
+                }
+            }
+        });
+
+        let pending = Pending::default();
+        let reader_pending = Arc::clone(&pending);
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader_closed = Arc::clone(&closed);
+        let reader_name = name.clone();
+        let reader_key = key.clone();
+        let (hello_tx, hello_rx) = oneshot::channel();
+        task::spawn_blocking(move || {
+            let mut output = SyncIoBridge::new(output);
+            let hello = read_frame::<Hello>(&mut output);
+            let session = match &hello {
+                Ok(Some(hello)) => Some(Session {
+                    worker: hello.challenge,
+                    bot: challenge,
+                }),
+                _ => None,
+            };
+            let _ = hello_tx.send(hello);
+            let Some(session) = session else {
+                return;
+            };
+            loop {
+                let msg = match read_frame::<Signed<Message>>(&mut output) {
+                    Ok(Some(Signed { msg, mac })) => {
+                        if let Some(key) = &reader_key
+                            && !mac.is_some_and(|mac| key.verify_message(&session, &msg, &mac))
+                        {
+                            log::error!(
+                                "Worker {reader_name} sent a message that is not authentic"
+                            );
+                            break;
+                        }
+                        msg
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("Failed to read from worker {reader_name}: {e}");
+                        break;
+                    }
+                };
+                match msg {
+                    Message::Progress { id, progress } => {
+                        if let Some(req) = reader_pending.lock().unwrap().get(&id) {
+                            let _ = req.progress.send(progress);
+                        }
+                    }
+                    Message::Response(res) => {
+                        match reader_pending.lock().unwrap().remove(&res.id) {
+                            Some(req) => {
+                                let _ = req.response.send(res);
+                            }
+                            None => log::warn!(
+                                "Worker {reader_name} responded to unknown request {}",
+                                res.id
+                            ),
+                        }
+                    }
+                }
+            }
+            // Nothing else will arrive, so don't leave anyone waiting
+            reader_closed.store(true, Ordering::SeqCst);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        let mut worker = Self {
+            connection,
+            channel: Channel {
+                name,
+                requests,
+                pending,
+                closed,
+                capabilities: Vec::new(),
+                key,
+                session: Session::default(),
+            },
+            capacity,
+            busy: 0,
+            jobs: 0,
+            failed: false,
+        };
+        let hello = match timeout(HANDSHAKE_TIMEOUT, hello_rx).await {
+            Ok(Ok(Ok(Some(hello)))) if hello.protocol_version == PROTOCOL_VERSION => Ok(hello),
+            Ok(Ok(Ok(Some(hello)))) => Err(format!(
+                "it speaks protocol version {}, expected {PROTOCOL_VERSION}",
+                hello.protocol_version
+            )),
+            Ok(Ok(Ok(None))) | Ok(Err(_)) => Err("it exited before introducing itself".into()),
+            Ok(Ok(Err(e))) => Err(e.to_string()),
+            Err(_) => Err("it did not introduce itself in time".into()),
+        };
+        match hello {
+            Ok(hello) => {
+                log::info!(
+                    "Worker {} is ready with {} in image {}",
+                    worker.channel.name,
+                    hello.toolchain,
+                    hello.image_digest.as_deref().unwrap_or("unknown")
+                );
+                worker.channel.capabilities = hello.capabilities;
+                worker.channel.session = Session {
+                    worker: hello.challenge,
+                    bot: challenge,
+                };
+                Ok(worker)
+            }
+            Err(e) => {
+                let err = format!("Refusing incompatible worker {}: {e}", worker.channel.name);
+                worker.failed = true;
+                worker.retire();
+                Err(err.into())
+            }
+        }
+    }
+
+    fn is_healthy(&mut self) -> bool {
+        let running = match &mut self.connection {
+            Connection::Process { child, .. } => matches!(child.try_wait(), Ok(None)),
+            // A remote worker that went away closes the connection, stopping the reader
+            Connection::Socket(_) => true,
+        };
+        !self.channel.closed.load(Ordering::SeqCst) && running
+    }
+
+    /// Whether `channel` sends requests to this worker
+    fn runs(&self, channel: &Channel) -> bool {
+        self.channel.requests.same_channel(&channel.requests)
+    }
+
+    /// Asks the worker to exit by closing its stdin, killing it if it doesn't. Workers that
+    /// failed are killed straight away. Remote workers are only disconnected from.
+    fn retire(self) {
+        let Self {
+            connection,
+            channel,
+            failed,
+            ..
+        } = self;
+        let name = channel.name;
+        drop(channel.requests);
+        let (backend, mut child) = match connection {
+            Connection::Process { backend, child } => (backend, child),
+            Connection::Socket(_) => {
+                log::info!("Disconnected from worker {name}");
+                return;
+            }
+        };
+        let grace = if failed { Duration::ZERO } else { RETIRE_GRACE };
+        tokio::spawn(async move {
+            if timeout(grace, child.wait()).await.is_err() {
+                log::warn!("Worker {name} did not exit, killing it");
+                let killed = match backend.kill_command(&name) {
+                    Some(mut cmd) => cmd.output().await.map(drop),
+                    None => child.kill().await,
+                };
+                if let Err(e) = killed {
+                    log::error!("Failed to kill worker {name}: {e}");
+                }
+            }
+            log::info!("Retired worker {name}");
+        });
+    }
+}
+
+impl Channel {
+    async fn send(
+        &self,
+        req: Request,
+        progress: mpsc::UnboundedSender<Progress>,
+    ) -> Result<Response, Error> {
+        let id = req.id;
+        let mac = self
+            .key
+            .as_ref()
+            .map(|key| key.sign_request(&self.session, &req));
+        let (response, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(id, PendingRequest { response, progress });
+        // The reader may have stopped before the request was added
+        if self.closed.load(Ordering::SeqCst)
+            || self.requests.send(Signed { msg: req, mac }).is_err()
+        {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(format!("Worker {} is not accepting requests", self.name).into());
+        }
+        rx.await
+            .map_err(|_| format!("Worker {} exited before responding to {id}", self.name).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use tokio::{process::Command, time::sleep};
+    use worker::{Language, Limits};
+
+    use super::*;
+    use crate::runner::tests::{runner_crate, worker};
+
+    #[tokio::test]
+    async fn remote_workers_run_several_jobs_over_one_connection() {
+        let Some(worker) = worker() else {
+            return;
+        };
+        let dir = env::temp_dir().join(format!("ferris-elf-remote-{}", process::id()));
+        runner_crate(&dir.join("runner"));
+        let socket = dir.join("worker.sock");
+        let _worker = Command::new(worker)
+            .env("RUNNER_DIR", dir.join("runner"))
+            .env("MAX_PARALLEL_JOBS", "2")
+            .env("WORKER_LISTEN", format!("unix:{}", socket.display()))
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        while !socket.exists() {
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        // Listed once for each job it runs at a time
+        let endpoint = Endpoint::Unix(socket);
+        let pool = WorkerPool::remote(vec![endpoint.clone(), endpoint], None, 10);
+        let (first, second) = (pool.get().await.unwrap(), pool.get().await.unwrap());
+        assert_eq!(pool.workers.lock().unwrap().len(), 1);
+
+        let req = |id| Request {
+            id,
+            part: Some(1),
+            inputs: vec![b"1".to_vec()],
+            code: b"pub fn run(input: &str) -> u64 { input }".to_vec(),
+            language: Language::Rust,
+            limits: Limits {
+                compile_timeout: Duration::from_secs(120),
+                run_timeout: Duration::from_secs(60),
+                max_instructions: None,
+            },
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        };
+        let (progress, _) = mpsc::unbounded_channel();
+        let (first_res, second_res) = tokio::join!(
+            first.run(req(1), progress.clone()),
+            second.run(req(2), progress)
+        );
+        for (id, res) in [(1, first_res), (2, second_res)] {
+            let res = res.unwrap();
+            assert_eq!(res.id, id);
+            assert!(
+                matches!(res.outcome, Outcome::CompileError(_)),
+                "{:?}",
+                res.outcome
+            );
+        }
+
+        // Kept for the jobs after them
+        drop((first, second));
+        assert_eq!(pool.workers.lock().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Duration};
+
+use poise::serenity_prelude::Http;
+use time::OffsetDateTime;
+use tokio::time::{interval, sleep};
+use tokio_util::task::AbortOnDropHandle;
+
+use crate::{
+    Data, Error,
+    database::{JobState, RunStatus},
+    runner::{handle_benchmark, show_abandoned},
+};
+
+/// How long a job may go without its lease being renewed before it's considered abandoned
+const LEASE_DURATION: Duration = Duration::from_secs(60);
+/// Jobs that have been claimed this many times without finishing are given up on
+const MAX_ATTEMPTS: u32 = 3;
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+fn lease_expiry() -> i64 {
+    now() + LEASE_DURATION.as_secs() as i64
+}
+
+/// Queues a benchmark for a run that has already been inserted into the database.
+pub async fn enqueue(http: Arc<Http>, data: Arc<Data>, run_id: i64) -> Result<(), Error> {
+    let job = data.database.insert_job(run_id).await?;
+    spawn(http, data, job);
+    Ok(())
+}
+
+/// Resumes every job left unfinished by a previous instance of the bot, then keeps
+/// watching for jobs whose lease has expired.
+pub async fn recover(http: Arc<Http>, data: Arc<Data>) -> Result<(), Error> {
+    data.database.release_jobs().await?;
+
+    let mut reaper = interval(LEASE_DURATION);
+    loop {
+        reaper.tick().await;
+        for job in data.database.fetch_claimable_jobs(now()).await? {
+            log::info!("Resuming job {job}");
+            spawn(Arc::clone(&http), Arc::clone(&data), job);
+        }
+    }
+}
+
+fn spawn(http: Arc<Http>, data: Arc<Data>, job: i64) {
+    tokio::spawn(async move {
+        if let Err(e) = process(&http, &data, job).await {
+            log::error!("Job {job} failed: {e}");
+            // Picked up again by the reaper, which also spaces out the attempts
+            if let Err(e) = data.database.retry_job(job).await {
+                log::error!("Failed to return job {job} to the queue: {e}");
+            }
+        }
+    });
+}
+
+async fn process(http: &Http, data: &Arc<Data>, id: i64) -> Result<(), Error> {
+    let database = &data.database;
+
+    if !database.claim_job(id, now(), lease_expiry()).await? {
+        // Someone else got to it first
+        return Ok(());
+    }
+
+    let job = database.fetch_job(id).await?;
+    if job.attempts >= MAX_ATTEMPTS {
+        log::warn!("Job {id} was attempted {MAX_ATTEMPTS} times, giving up");
+        return fail(
+            http,
+            data,
+            id,
+            "The run was abandoned after too many attempts.",
+        )
+        .await;
+    }
+
+    // Dropped along with this future, so a panicking job stops renewing its lease
+    let _heartbeat = AbortOnDropHandle::new(tokio::spawn({
+        let data = Arc::clone(data);
+        async move {
+            loop {
+                sleep(LEASE_DURATION / 2).await;
+                if let Err(e) = data.database.renew_lease(id, lease_expiry()).await {
+                    log::error!("Failed to renew lease for job {id}: {e}");
+                }
+            }
+        }
+    }));
+
+    handle_benchmark(http, data, job.run_id).await?;
+
+    database.finish_job(id, JobState::Done).await?;
+
+    Ok(())
+}
+
+async fn fail(http: &Http, data: &Data, id: i64, error: &str) -> Result<(), Error> {
+    let job = data.database.fetch_job(id).await?;
+    data.database
+        .set_run_status(job.run_id, RunStatus::Failed, Some(error))
+        .await?;
+    data.database.finish_job(id, JobState::Failed).await?;
+    show_abandoned(http, data, job.run_id).await
+}
@@ -1,133 +1,495 @@
-use std::process::Stdio;
+use poise::serenity_prelude::{ChannelId, CreateMessage, EditMessage, Http, MessageId, UserId};
+use tokio::{join, sync::mpsc};
+use worker::{Build, Outcome, Progress, Request};
 
-use poise::serenity_prelude::{CreateMessage, Http, UserId, futures::channel::oneshot};
-use tokio::{io::BufReader, process::Command, task::JoinSet};
-use tokio_util::io::SyncIoBridge;
-use worker::{Request, Response};
+use crate::{
+    Data, Error,
+    database::{Language, Mode, PartScore, Run, RunResult, RunStatus},
+    golf,
+};
 
-use crate::{Data, Error};
-
-pub async fn handle_benchmark(
-    http: &Http,
-    data: &Data,
-    user: UserId,
-    day: u8,
-    part: u8,
-    code: Vec<u8>,
-) -> Result<(), Error> {
+pub async fn handle_benchmark(http: &Http, data: &Data, run: i64) -> Result<(), Error> {
     let database = &data.database;
 
-    let uuser = user.to_user(http).await?;
+    let Run {
+        id: rid,
+        submitter: user,
+        year,
+        day,
+        part,
+        code,
+        language,
+        reply,
+    } = database.fetch_run(run).await?;
+    // Tokens are only counted for Rust, other languages are left off that leaderboard
+    let code_tokens = match language {
+        Language::Rust => golf::count_tokens(&code),
+        _ => None,
+    };
 
-    let rid = database.insert_run(user, day, part, &code).await?;
+    let reply = Reply {
+        http,
+        run: rid,
+        submitter: user,
+        message: reply,
+    };
 
     let mut input_watch = data.input_watch.subscribe();
     let mut consensus_watch = data.consensus_watch.subscribe();
 
-    while database.inputs_count(day).await? < 3 {
-        input_watch.wait_for(|&d| day == d).await?;
+    if database.inputs_count(year, day).await? < 3 {
+        database
+            .set_run_status(rid, RunStatus::WaitingForInputs, None)
+            .await?;
+        reply
+            .show(format!("Waiting for more inputs for day {day}."))
+            .await;
+        while database.inputs_count(year, day).await? < 3 {
+            input_watch.changed().await?;
+        }
     }
 
-    let (ids, inputs) = database.fetch_inputs(day, 3).await?;
+    let (ids, inputs) = database.fetch_inputs(year, day, 3).await?;
 
-    let res = run_container(rid, inputs, code).await?;
+    // Code solving both parts is scored for each part separately
+    let parts = match part {
+        Some(part) => vec![part],
+        None => vec![1, 2],
+    };
 
-    if let Some(Err(err)) = res.outputs.iter().find(|res| res.is_err()) {
-        uuser
-            .direct_message(
-                http,
-                CreateMessage::new().content(format!(
-                    "Run failed with the following output:\n```{err}```"
-                )),
+    let mut results = database.fetch_run_results(rid).await?;
+    if results.is_empty() {
+        let mode = database.fetch_day_mode(year, day).await?;
+        let res = {
+            let worker = data.workers.get().await?;
+            database
+                .set_run_status(rid, RunStatus::Building, None)
+                .await?;
+            let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+            let req = Request {
+                id: rid,
+                part,
+                inputs,
+                code,
+                language: language.into(),
+                limits: data.limits,
+                callgrind_args: data.callgrind_args.clone(),
+                wall_clock: (mode == Mode::WallClock).then_some(data.wall_clock),
+            };
+            let (res, ()) = join!(
+                worker.run(req, progress_tx),
+                show_progress(data, &reply, progress_rx)
+            );
+            res?
+        };
+
+        let outputs = match res.outcome {
+            Outcome::Success { parts, build } => {
+                database.set_run_build(rid, &build).await?;
+                parts
+            }
+            // Not the submission's fault, so it is tried again
+            Outcome::InfrastructureError(err) => {
+                return Err(format!("The worker failed: {err}").into());
+            }
+            outcome => {
+                let message = failure_message(&outcome);
+                database
+                    .set_run_status(rid, RunStatus::Failed, Some(&message))
+                    .await?;
+                reply
+                    .show("Your submission failed, check your DMs for why.")
+                    .await;
+                reply.tell(message).await;
+                return Ok(());
+            }
+        };
+        if !outputs
+            .iter()
+            .map(|output| output.part)
+            .eq(parts.iter().copied())
+        {
+            return Err(
+                format!("Worker returned results for the wrong parts, expected {parts:?}").into(),
+            );
+        }
+        if let Some(output) = outputs
+            .iter()
+            .find(|output| output.inputs.len() != ids.len())
+        {
+            return Err(format!(
+                "Worker returned {} results for {} inputs",
+                output.inputs.len(),
+                ids.len()
             )
+            .into());
+        }
+
+        results = outputs
+            .into_iter()
+            .flat_map(|output| {
+                let part = output.part;
+                output
+                    .inputs
+                    .into_iter()
+                    .zip(&ids)
+                    .map(move |(input, &input_id)| RunResult {
+                        part,
+                        input_id,
+                        answer: input.answer,
+                        score: input.instructions,
+                        parse_score: input.parse_instructions,
+                        solve_score: input.solve_instructions,
+                        metrics: input.metrics,
+                        timing: input.timing,
+                        peak_heap: Some(input.peak_heap),
+                    })
+            })
+            .collect();
+        database.insert_run_results(rid, user, &results).await?;
+        data.consensus_watch.send_replace(());
+    }
+
+    let details = build_details(database.fetch_run_build(rid).await?);
+
+    database
+        .set_run_status(rid, RunStatus::AwaitingConsensus, None)
+        .await?;
+    reply
+        .show("Benchmarked, waiting for the consensus to check your answers.")
+        .await;
+
+    let mut wrong = Vec::new();
+    for &part in &parts {
+        let results = results
+            .iter()
+            .filter(|res| res.part == part)
+            .collect::<Vec<_>>();
+
+        let mut correct = true;
+        for res in &results {
+            let id = res.input_id;
+            while database.solution_consensus(id, part).await?.is_none() {
+                consensus_watch.changed().await?;
+            }
+            let consensus = database.solution_consensus(id, part).await?.unwrap();
+            if res.answer != consensus {
+                correct = false;
+                break;
+            }
+        }
+        if !correct {
+            wrong.push(part);
+            continue;
+        }
+
+        let avg_time = results.iter().map(|res| res.score).sum::<u64>() / results.len() as u64;
+        let score = PartScore {
+            score: avg_time as _,
+            parse_score: average(results.iter().map(|res| res.parse_score)),
+            solve_score: average(results.iter().map(|res| res.solve_score)),
+            median_time: average(
+                results
+                    .iter()
+                    .map(|res| res.timing.map(|t| t.median.as_nanos() as u64)),
+            ),
+            peak_heap: average(results.iter().map(|res| res.peak_heap)),
+            code_tokens: code_tokens.map(|t| t as i64),
+        };
+        database.insert_score(rid, part, &score).await?;
+    }
+
+    if wrong.is_empty() {
+        database
+            .set_run_status(rid, RunStatus::Scored, None)
             .await?;
+        reply
+            .show(format!("Done! Your submission has been scored.{details}"))
+            .await;
         return Ok(());
     }
 
-    let outputs = res
-        .outputs
-        .into_iter()
-        .map(|o| o.unwrap())
-        .collect::<Vec<_>>();
-    for (&res, &id) in outputs.iter().zip(&ids) {
-        database.insert_solution(user, id, part, res).await?;
+    database
+        .set_run_status(rid, RunStatus::WrongAnswer, None)
+        .await?;
+    reply
+        .show(format!(
+            "Done, but your answers did not match the consensus.{details}"
+        ))
+        .await;
+    // Parts that did match still count when only one of two was wrong
+    let message = match wrong.as_slice() {
+        &[part] if parts.len() > 1 => format!(
+            "The solution provided by your code for part {part} did not match the consensus solution."
+        ),
+        _ => "The solution provided by your code did not match the consensus solution.".to_owned(),
+    };
+    reply.tell(message).await;
+
+    Ok(())
+}
+
+/// Tells the submitter their run was given up on after going wrong on our end
+pub async fn show_abandoned(http: &Http, data: &Data, run: i64) -> Result<(), Error> {
+    let run = data.database.fetch_run(run).await?;
+    let reply = Reply {
+        http,
+        run: run.id,
+        submitter: run.submitter,
+        message: run.reply,
+    };
+    reply
+        .show("Your submission failed, check your DMs for why.")
+        .await;
+    reply
+        .tell(failure_message(
+            &Outcome::InfrastructureError(String::new()),
+        ))
+        .await;
+    Ok(())
+}
+
+/// The reply to `/aoc run`, which is edited to show how the run is getting on
+struct Reply<'a> {
+    http: &'a Http,
+    run: i64,
+    submitter: UserId,
+    message: Option<(ChannelId, MessageId)>,
+}
+
+impl Reply<'_> {
+    /// Progress is only a courtesy, so failing to show it doesn't fail the run
+    async fn show(&self, content: impl Into<String>) {
+        let Some((channel, message)) = self.message else {
+            return;
+        };
+        let edit = EditMessage::new().content(content);
+        if let Err(e) = channel.edit_message(self.http, message, edit).await {
+            log::warn!("Failed to show the progress of run {}: {e}", self.run);
+        }
     }
 
-    let mut avg_time = 0;
-    for ((&res, &id), &time) in outputs.iter().zip(&ids).zip(&res.times) {
-        while database.solution_consensus(id).await?.is_none() {
-            consensus_watch.wait_for(|&i| id == i).await?;
+    /// DMs the submitter what went wrong. The outcome is already recorded by then, and
+    /// submitters may not accept DMs, so failing to doesn't fail the run either.
+    async fn tell(&self, content: impl Into<String>) {
+        let message = CreateMessage::new().content(content);
+        if let Err(e) = self.submitter.direct_message(self.http, message).await {
+            log::warn!("Failed to DM the submitter of run {}: {e}", self.run);
         }
-        let consensus = database.solution_consensus(id).await?.unwrap();
-        if res != consensus {
-            uuser
-                .direct_message(
-                    http,
-                    CreateMessage::new().content(format!(
-                        "The solution provided by your code did not match the consensus solution."
-                    )),
-                )
-                .await?;
-            return Ok(());
+    }
+}
+
+/// Shows the progress reported by the worker until it has responded
+async fn show_progress(
+    data: &Data,
+    reply: &Reply<'_>,
+    mut progress: mpsc::UnboundedReceiver<Progress>,
+) {
+    // Kept around so they can still be read while benchmarking
+    let mut warnings = String::new();
+    while let Some(event) = progress.recv().await {
+        let status = match event {
+            Progress::Accepted => "Your submission has been picked up.".to_owned(),
+            Progress::Compiling => "Compiling your submission...".to_owned(),
+            Progress::Compiled { warnings: w } => {
+                warnings = w;
+                "Compiled.".to_owned()
+            }
+            Progress::Benchmarking { current, total } => {
+                if current == 1 {
+                    let res = data
+                        .database
+                        .set_run_status(reply.run, RunStatus::Benchmarking, None)
+                        .await;
+                    if let Err(e) = res {
+                        log::warn!("Failed to update the status of run {}: {e}", reply.run);
+                    }
+                }
+                format!("Benchmarking {current} of {total}...")
+            }
+            Progress::Timing { current, total } => format!("Timing input {current} of {total}..."),
+        };
+        if warnings.is_empty() {
+            reply.show(status).await;
+        } else {
+            reply
+                .show(format!(
+                    "{status}\nWarnings:\n```{}```",
+                    truncate(&warnings, MAX_OUTPUT_LEN)
+                ))
+                .await;
         }
-        avg_time += time;
     }
-    avg_time /= res.times.len() as u64;
+}
 
-    database.update_run(rid, avg_time as _).await?;
+/// The average of the scores, if every result has one
+fn average(scores: impl ExactSizeIterator<Item = Option<u64>>) -> Option<i64> {
+    let len = scores.len() as u64;
+    let total = scores.sum::<Option<u64>>()?;
+    Some((total / len) as i64)
+}
 
-    Ok(())
+/// What building the run took and produced, on a line of its own
+fn build_details(build: Option<Build>) -> String {
+    match build {
+        Some(build) => format!(
+            "\nCompiled in {:.2?} into {} codegen units, with {} bytes of `.text`.",
+            build.compile_time, build.codegen_units, build.text_size
+        ),
+        None => String::new(),
+    }
+}
+
+/// Explains to the submitter why their run did not produce a score
+fn failure_message(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Success { .. } => unreachable!("successful runs have not failed"),
+        Outcome::CompileError(err) => format!(
+            "Your code failed to compile:\n```{}```",
+            truncate(err, MAX_OUTPUT_LEN)
+        ),
+        Outcome::Panic { input, message } => format!(
+            "Your code panicked on input {}:\n```{}```",
+            input + 1,
+            truncate(message, MAX_OUTPUT_LEN)
+        ),
+        Outcome::CompileTimeout => "Your code took too long to compile.".to_owned(),
+        Outcome::RunTimeout | Outcome::Timeout => "Your code took too long to run.".to_owned(),
+        Outcome::InstructionLimit {
+            input,
+            instructions,
+        } => format!(
+            "Your code used {instructions} instructions on input {}, more than it was allowed to.",
+            input + 1
+        ),
+        Outcome::MemoryLimit => "Your code used more memory than it was allowed to.".to_owned(),
+        Outcome::InvalidResults(err) => format!(
+            "The results of your run could not be trusted:\n```{}```",
+            truncate(err, MAX_OUTPUT_LEN)
+        ),
+        Outcome::InfrastructureError(_) => {
+            "Something went wrong on our end while running your code. Please try again later."
+                .to_owned()
+        }
+    }
+}
+
+/// Discord messages are limited to 2000 characters, leave room for the surrounding text
+const MAX_OUTPUT_LEN: usize = 1800;
+
+fn truncate(s: &str, len: usize) -> &str {
+    match s.char_indices().nth(len) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
 }
 
-async fn run_container(id: i64, inputs: Vec<Vec<u8>>, code: Vec<u8>) -> Result<Response, Error> {
-    let name = format!("runner-{id}");
-    let mut child = Command::new("docker")
-        .args(&[
-            "run",
-            "--cap-drop",
-            "--net",
-            "none",
-            "--cpus",
-            "2",
-            "--memory",
-            "512m",
-            "--memory-swap",
-            "640m",
-            "--oom-score-adj",
-            "1000",
-        ])
-        .args(&["--name", &name])
-        .arg("-i")
-        .args(["-a", "stdin", "-a", "stdout", "-a", "stderr"])
-        .arg("--rm")
-        .arg("runner")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let mut io = JoinSet::<Result<(), Error>>::new();
-
-    let stdin = child.stdin.take().unwrap();
-    let stdout = BufReader::new(child.stdout.take().unwrap());
-    let (tx, rx) = oneshot::channel();
-
-    io.spawn_blocking(move || {
-        let mut stdin = SyncIoBridge::new(stdin);
-        let req = Request { id, inputs, code };
-
-        bincode::serialize_into(&mut stdin, &req)?;
-        Ok(())
-    });
-    io.spawn_blocking(move || {
-        let mut stdout = SyncIoBridge::new(stdout);
-        let res = bincode::deserialize_from(&mut stdout)?;
-        tx.send(res).unwrap();
-        Ok(())
-    });
-
-    io.join_all().await;
-
-    let res = rx.await?;
-    Ok(res)
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use poise::serenity_prelude::UserId;
+    use tokio::sync::watch;
+    use worker::{Limits, WallClock};
+
+    use super::*;
+    use crate::{database::Database, pool::WorkerPool, sandbox::Local};
+
+    /// The worker binary CI built, like the local backend takes it
+    pub(crate) fn worker() -> Option<PathBuf> {
+        let worker = env::var_os("WORKER_PATH").map(PathBuf::from);
+        if worker.is_none() {
+            eprintln!("Not running a worker without WORKER_PATH");
+        }
+        worker
+    }
+
+    /// A stand-in for the runner crate without any dependencies
+    pub(crate) fn runner_crate(dir: &Path) {
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("benches")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "runner"
+version = "0.1.0"
+edition = "2024"
+
+[[bench]]
+name = "bench"
+harness = false
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("benches/bench.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_that_fail_to_compile_are_failed() {
+        let Some(worker) = worker() else {
+            return;
+        };
+        let runner_dir = env::temp_dir().join(format!("ferris-elf-runner-{}", std::process::id()));
+        runner_crate(&runner_dir);
+        let backend = Local {
+            worker,
+            runner_dir: runner_dir.clone(),
+        };
+
+        let database = Database::in_memory().await.unwrap();
+        let user = UserId::new(1);
+        for input in ["1", "2", "3"] {
+            database
+                .insert_input(user, 2024, 1, input.as_bytes())
+                .await
+                .unwrap();
+        }
+        let run = database
+            .insert_run(
+                user,
+                2024,
+                1,
+                Some(1),
+                b"pub fn run(input: &str) -> u64 { input }",
+                Language::Rust,
+                None,
+            )
+            .await
+            .unwrap();
+        let data = Data {
+            database,
+            input_watch: watch::channel(()).0,
+            consensus_watch: watch::channel(()).0,
+            workers: WorkerPool::new(Box::new(backend), 1, 1),
+            limits: Limits {
+                compile_timeout: Duration::from_secs(120),
+                run_timeout: Duration::from_secs(60),
+                max_instructions: None,
+            },
+            callgrind_args: Vec::new(),
+            wall_clock: WallClock {
+                warmup: Duration::ZERO,
+                samples: 1,
+            },
+        };
+
+        // Nothing is sent to Discord but the DM of why, which is only logged when it fails
+        let http = Http::new("");
+        handle_benchmark(&http, &data, run).await.unwrap();
+
+        let (status, error) = data.database.fetch_run_status(run).await.unwrap();
+        assert_eq!(status, RunStatus::Failed);
+        let error = error.unwrap();
+        assert!(
+            error.starts_with("Your code failed to compile") && error.contains("mismatched types"),
+            "{error}"
+        );
+        fs::remove_dir_all(runner_dir).unwrap();
+    }
 }
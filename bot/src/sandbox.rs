@@ -0,0 +1,400 @@
+//! The ways a worker can be started. Every backend runs the worker binary from
+//! `resources/worker`, speaking the protocol over its stdin and stdout, but they differ in
+//! how it is isolated from the host.
+
+use std::{env, path::PathBuf, str::FromStr};
+
+use tokio::process::Command;
+
+use crate::Error;
+
+/// The image built from `resources/Dockerfile`
+const IMAGE: &str = "runner";
+/// The user that image runs the worker as
+const WORKER_UID: u32 = 10000;
+
+pub trait SandboxBackend: Send + Sync {
+    /// A command starting a worker named `name`
+    fn command(&self, name: &str) -> Command;
+
+    /// A command forcefully stopping the worker named `name`, for backends where killing
+    /// the process started by [`SandboxBackend::command`] is not enough
+    fn kill_command(&self, _name: &str) -> Option<Command> {
+        None
+    }
+}
+
+/// Limits applied to every worker, as far as the backend supports them
+#[derive(Clone, Debug)]
+pub struct SandboxPolicy {
+    pub cpus: f32,
+    /// In bytes
+    pub memory: u64,
+    /// Memory plus swap, in bytes
+    pub memory_swap: u64,
+    /// The most processes and threads that may exist at once
+    pub pids_limit: u32,
+    /// The size of the writable scratch dir at `/tmp`, in bytes. Everything else is
+    /// read-only apart from the runner crates.
+    pub scratch_size: u64,
+    /// The size of the volume each runner crate is built in, in bytes. It is held in memory
+    /// like the scratch dir, but only what is written counts towards `memory`.
+    pub runner_size: u64,
+    /// The largest file that may be written, in bytes
+    pub max_file_size: u64,
+    pub max_open_files: u64,
+    /// A seccomp profile to use instead of the runtime's default one
+    pub seccomp_profile: Option<PathBuf>,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        Self {
+            cpus: 2.0,
+            memory: 512 << 20,
+            memory_swap: 640 << 20,
+            pids_limit: 256,
+            scratch_size: 64 << 20,
+            runner_size: 1 << 30,
+            max_file_size: 256 << 20,
+            max_open_files: 1024,
+            seccomp_profile: None,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// The default policy, with any limits given by `SANDBOX_*` variables replaced
+    pub fn from_env() -> Result<Self, Error> {
+        let default = Self::default();
+        Ok(Self {
+            cpus: env_or("SANDBOX_CPUS", default.cpus)?,
+            memory: env_size_or("SANDBOX_MEMORY", default.memory)?,
+            memory_swap: env_size_or("SANDBOX_MEMORY_SWAP", default.memory_swap)?,
+            pids_limit: env_or("SANDBOX_PIDS_LIMIT", default.pids_limit)?,
+            scratch_size: env_size_or("SANDBOX_SCRATCH_SIZE", default.scratch_size)?,
+            runner_size: env_size_or("SANDBOX_RUNNER_SIZE", default.runner_size)?,
+            max_file_size: env_size_or("SANDBOX_MAX_FILE_SIZE", default.max_file_size)?,
+            max_open_files: env_or("SANDBOX_MAX_OPEN_FILES", default.max_open_files)?,
+            seccomp_profile: env::var_os("SANDBOX_SECCOMP_PROFILE").map(PathBuf::from),
+        })
+    }
+}
+
+/// Picks the backend named by `SANDBOX_BACKEND`, defaulting to docker
+pub fn from_env() -> Result<Box<dyn SandboxBackend>, Error> {
+    let backend = env::var("SANDBOX_BACKEND").unwrap_or_else(|_| "docker".into());
+    let policy = SandboxPolicy::from_env()?;
+    Ok(match backend.as_str() {
+        "docker" => Box::new(Container::new("docker", policy)),
+        "podman" => Box::new(Container::new("podman", policy)),
+        "bubblewrap" => Box::new(Bubblewrap {
+            worker: env_path("WORKER_PATH")?,
+            runner_dir: env_path("RUNNER_DIR")?,
+            native_dir: env::var_os("NATIVE_RUNNER_DIR").map(PathBuf::from),
+            policy,
+        }),
+        "local" => Box::new(Local {
+            worker: env_path("WORKER_PATH")?,
+            runner_dir: env_path("RUNNER_DIR")?,
+        }),
+        _ => return Err(format!("Unknown sandbox backend {backend:?}").into()),
+    })
+}
+
+fn env_path(var: &str) -> Result<PathBuf, Error> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("{var} must be set for this sandbox backend").into())
+}
+
+fn env_or<T: FromStr>(var: &str, default: T) -> Result<T, Error> {
+    match env::var(var) {
+        Ok(value) => value.parse().map_err(|_| format!("invalid {var}").into()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Like [`env_or`], but also accepts sizes such as `512m`
+fn env_size_or(var: &str, default: u64) -> Result<u64, Error> {
+    let Ok(value) = env::var(var) else {
+        return Ok(default);
+    };
+    let (digits, shift) = match value.to_ascii_lowercase().chars().last() {
+        Some('k') => (&value[..value.len() - 1], 10),
+        Some('m') => (&value[..value.len() - 1], 20),
+        Some('g') => (&value[..value.len() - 1], 30),
+        _ => (&value[..], 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid {var}").into())
+}
+
+/// Runs workers in containers using docker, or anything with a compatible command line
+pub struct Container {
+    program: &'static str,
+    policy: SandboxPolicy,
+}
+
+impl Container {
+    pub fn new(program: &'static str, policy: SandboxPolicy) -> Self {
+        Self { program, policy }
+    }
+}
+
+impl SandboxBackend for Container {
+    fn command(&self, name: &str) -> Command {
+        let policy = &self.policy;
+        let mut cmd = Command::new(self.program);
+        cmd.arg("run")
+            .args(["--cap-drop", "ALL"])
+            // Switching to the users builds and submissions run as is the only privilege
+            // the worker needs. It reaches their files through their groups and kills
+            // their processes as them, and nothing it starts keeps either capability.
+            .args(["--cap-add", "SETUID", "--cap-add", "SETGID"])
+            // Reaps whatever submissions leave behind, which the worker kills but isn't
+            // the parent of
+            .arg("--init")
+            .args(["--security-opt", "no-new-privileges"])
+            .args(["--network", "none"])
+            .args(["--cpus", &policy.cpus.to_string()])
+            .args(["--memory", &policy.memory.to_string()])
+            .args(["--memory-swap", &policy.memory_swap.to_string()])
+            .args(["--oom-score-adj", "1000"])
+            .args(["--pids-limit", &policy.pids_limit.to_string()])
+            .arg("--read-only")
+            // Only the worker may create files in it, so submissions can't leave any
+            // behind for the builds of others to include, and there's no /dev/shm either
+            .arg("--tmpfs")
+            .arg(format!(
+                "/tmp:rw,nosuid,nodev,mode=1771,uid={WORKER_UID},gid={WORKER_UID},size={}",
+                policy.scratch_size
+            ))
+            .args(["--ipc", "none"])
+            .arg("--ulimit")
+            .arg(format!("fsize={0}:{0}", policy.max_file_size))
+            .arg("--ulimit")
+            .arg(format!("nofile={0}:{0}", policy.max_open_files));
+        // The runner crates are built in place, so each gets a volume of its own which
+        // starts out with the image's copy and is removed along with the container.
+        // Backed by a tmpfs so that it can't fill the host's disk.
+        for runner_dir in ["/runner", "/native"] {
+            cmd.arg("--mount").arg(format!(
+                "type=volume,destination={runner_dir},volume-opt=type=tmpfs,\
+                    volume-opt=device=tmpfs,volume-opt=o=size={}",
+                policy.runner_size
+            ));
+        }
+        if let Some(profile) = &policy.seccomp_profile {
+            cmd.arg("--security-opt")
+                .arg(format!("seccomp={}", profile.display()));
+        }
+        cmd.args(["--name", name])
+            .arg("-i")
+            .args(["-a", "stdin", "-a", "stdout", "-a", "stderr"])
+            .arg("--rm")
+            .arg(IMAGE);
+        cmd
+    }
+
+    fn kill_command(&self, name: &str) -> Option<Command> {
+        let mut cmd = Command::new(self.program);
+        cmd.args(["kill", name]);
+        Some(cmd)
+    }
+}
+
+/// Runs workers as local processes in a bubblewrap sandbox, for hosts without a container
+/// runtime. The host's toolchain and valgrind are used. Only the file limits, the scratch
+/// size and dropping capabilities apply, memory, CPU, process and seccomp limits need a
+/// container runtime.
+pub struct Bubblewrap {
+    worker: PathBuf,
+    /// The runner crate, already built. Each worker sees it at `/runner` with its own
+    /// temporary overlay on top, so workers can't see each other's changes.
+    runner_dir: PathBuf,
+    /// The runner crate for other languages, seen at `/native` the same way. Workers only
+    /// support Rust without it.
+    native_dir: Option<PathBuf>,
+    policy: SandboxPolicy,
+}
+
+impl SandboxBackend for Bubblewrap {
+    fn command(&self, _name: &str) -> Command {
+        let policy = &self.policy;
+        let mut cmd = Command::new("prlimit");
+        cmd.arg(format!("--fsize={}", policy.max_file_size))
+            .arg(format!("--nofile={}", policy.max_open_files))
+            .args(["--", "bwrap"])
+            .args(["--ro-bind", "/", "/"])
+            .args(["--dev", "/dev", "--proc", "/proc"])
+            .args([
+                "--size",
+                &policy.scratch_size.to_string(),
+                "--tmpfs",
+                "/tmp",
+            ])
+            .arg("--overlay-src")
+            .arg(&self.runner_dir)
+            .args(["--tmp-overlay", "/runner"])
+            .args(["--unshare-all", "--die-with-parent", "--new-session"])
+            .args(["--cap-drop", "ALL"])
+            .args(["--setenv", "RUNNER_DIR", "/runner"]);
+        if let Some(native_dir) = &self.native_dir {
+            cmd.arg("--overlay-src")
+                .arg(native_dir)
+                .args(["--tmp-overlay", "/native"])
+                .args(["--setenv", "NATIVE_RUNNER_DIR", "/native"]);
+        }
+        cmd.arg("--").arg(&self.worker);
+        cmd
+    }
+}
+
+/// Runs the worker binary directly without any isolation. Only for trusted code, such as
+/// in tests. Every worker uses the same runner crate, so only one may run at a time.
+pub struct Local {
+    pub worker: PathBuf,
+    pub runner_dir: PathBuf,
+}
+
+impl SandboxBackend for Local {
+    fn command(&self, _name: &str) -> Command {
+        let mut cmd = Command::new(&self.worker);
+        cmd.env("RUNNER_DIR", &self.runner_dir);
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use worker::{Language, Limits, Outcome, Request};
+
+    use super::*;
+    use crate::pool::WorkerPool;
+
+    /// Runs a submission against the sandbox, returning how it fared
+    async fn run_hostile(code: &str, run_timeout: Duration) -> Outcome {
+        let backend = Container::new("docker", SandboxPolicy::default());
+        let pool = WorkerPool::new(Box::new(backend), 1, 1);
+        let worker = pool.get().await.unwrap();
+        let req = Request {
+            id: 0,
+            part: Some(1),
+            inputs: vec![b"hostile".to_vec(); 3],
+            code: code.into(),
+            language: Language::Rust,
+            limits: Limits {
+                compile_timeout: Duration::from_secs(120),
+                run_timeout,
+                max_instructions: None,
+            },
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        };
+        // Progress isn't needed, so it goes nowhere
+        let (progress, _) = mpsc::unbounded_channel();
+        worker.run(req, progress).await.unwrap().outcome
+    }
+
+    /// Asserts that the submission panicked on an error of the given kind, which is how
+    /// the submissions below tell that the sandbox stopped them
+    fn assert_stopped_by(outcome: Outcome, kind: &str) {
+        match outcome {
+            Outcome::Panic { message, .. } => {
+                assert!(message.contains(kind), "expected {kind}, got {message}");
+            }
+            outcome => panic!("expected the submission to fail with {kind}, got {outcome:?}"),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker and the runner image"]
+    async fn fork_bomb() {
+        // Never gives up, so only running out of time or memory stops it
+        let outcome = run_hostile(
+            r#"
+pub fn run(_: &str) -> &'static str {
+    loop {
+        let _ = std::process::Command::new("sleep").arg("10").spawn();
+    }
+}"#,
+            Duration::from_secs(20),
+        )
+        .await;
+        assert!(
+            matches!(outcome, Outcome::RunTimeout | Outcome::MemoryLimit),
+            "got {outcome:?}"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker and the runner image"]
+    async fn disk_fill() {
+        // Many files, none of them past the file size limit, in everywhere it can create
+        // them: the runner crate, the scratch dir and the dir of the request within it
+        let outcome = run_hostile(
+            r#"
+use std::{env, fs, io::Write, path::Path};
+
+pub fn run(_: &str) -> &'static str {
+    let job_dir = Path::new(&env::var_os("INPUTS_DIR").unwrap()).parent().unwrap().to_owned();
+    let chunk = vec![0; 1 << 20];
+    for dir in [Path::new("/runner"), Path::new("/tmp"), &job_dir] {
+        for i in 0..128 {
+            let Ok(mut file) = fs::File::create(dir.join(format!("fill{i}"))) else {
+                break;
+            };
+            for _ in 0..16 {
+                file.write_all(&chunk).expect("filling the disk");
+            }
+        }
+    }
+    "escaped"
+}"#,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_stopped_by(outcome, "StorageFull");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker and the runner image"]
+    async fn read_only_root() {
+        // Writable by everyone in the image the runner image is based on
+        let outcome = run_hostile(
+            r#"
+pub fn run(_: &str) -> &'static str {
+    std::fs::write("/usr/local/cargo/bin/ferris-elf", "").expect("writing to the image");
+    "escaped"
+}"#,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_stopped_by(outcome, "ReadOnlyFilesystem");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker and the runner image"]
+    async fn network_access() {
+        let outcome = run_hostile(
+            r#"
+use std::{net::TcpStream, time::Duration};
+
+pub fn run(_: &str) -> &'static str {
+    let addr = "1.1.1.1:53".parse().unwrap();
+    TcpStream::connect_timeout(&addr, Duration::from_secs(5)).expect("connecting out");
+    "escaped"
+}"#,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_stopped_by(outcome, "NetworkUnreachable");
+    }
+}
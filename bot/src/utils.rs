@@ -3,9 +3,15 @@ use time::{OffsetDateTime, macros::offset};
 
 use crate::Context;
 
-/// Today as defined by the Advent of Code Timezone
-pub fn aoc_today() -> u8 {
-    OffsetDateTime::now_utc().to_offset(offset!(-5:00)).day()
+/// Today as defined by the Advent of Code Timezone, as `(year, day)`
+pub fn aoc_today() -> (i32, u8) {
+    let now = OffsetDateTime::now_utc().to_offset(offset!(-5:00));
+    (now.year(), now.day())
+}
+
+/// Whether the day is over, which is when the next puzzle unlocks
+pub fn day_closed(year: i32, day: u8) -> bool {
+    (year, day) < aoc_today()
 }
 
 pub async fn get_name(ctx: &Context<'_>, user: UserId) -> String {
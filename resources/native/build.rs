@@ -0,0 +1,17 @@
+//! Links the submission, which the worker compiles into `libsubmission.a` in this crate's dir
+//! before building the harness.
+
+use std::env;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo::rerun-if-changed=libsubmission.a");
+    println!("cargo::rerun-if-env-changed=SUBMISSION_LINK");
+    println!("cargo::rustc-link-search=native={dir}");
+    println!("cargo::rustc-link-lib=static=submission");
+    // Such as the C++ standard library
+    let link = env::var("SUBMISSION_LINK").unwrap_or_default();
+    for lib in link.split(',').filter(|lib| !lib.is_empty()) {
+        println!("cargo::rustc-link-lib={lib}");
+    }
+}
@@ -0,0 +1,82 @@
+//! Calls into a submission in another language through the C ABI, so that the harness can
+//! benchmark it like a Rust one. See `submission.c` for the functions it defines.
+
+#[cfg(feature = "split")]
+use std::ffi::c_void;
+use std::fmt::{self, Display};
+
+/// The longest answer a submission can give, in bytes
+const ANSWER_CAP: usize = 256;
+
+/// The answer a submission wrote, kept on the stack so that nothing is allocated on its behalf
+pub struct Answer {
+    buf: [u8; ANSWER_CAP],
+    len: usize,
+}
+
+impl Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.buf[..self.len]))
+    }
+}
+
+/// Lets the submission write its answer, given where to and how much room there is
+fn answer(write: impl FnOnce(*mut u8, usize) -> usize) -> Answer {
+    let mut buf = [0; ANSWER_CAP];
+    let len = write(buf.as_mut_ptr(), ANSWER_CAP);
+    assert!(
+        len <= ANSWER_CAP,
+        "answer of {len} bytes, the most is {ANSWER_CAP}"
+    );
+    Answer { buf, len }
+}
+
+mod submission {
+    #[cfg(feature = "split")]
+    use std::ffi::c_void;
+
+    unsafe extern "C" {
+        #[cfg(not(any(feature = "split", feature = "parts")))]
+        pub fn run(input: *const u8, len: usize, out: *mut u8, cap: usize) -> usize;
+        #[cfg(feature = "split")]
+        pub fn parse(input: *const u8, len: usize) -> *mut c_void;
+        #[cfg(feature = "split")]
+        pub fn solve(parsed: *mut c_void, out: *mut u8, cap: usize) -> usize;
+        #[cfg(feature = "parts")]
+        pub fn part1(input: *const u8, len: usize, out: *mut u8, cap: usize) -> usize;
+        #[cfg(feature = "parts")]
+        pub fn part2(input: *const u8, len: usize, out: *mut u8, cap: usize) -> usize;
+    }
+}
+
+// SAFETY: The submission is trusted to keep to the signatures, like a Rust one is trusted
+// not to misuse unsafe code. It runs in the sandbox either way.
+
+#[cfg(not(any(feature = "split", feature = "parts")))]
+pub fn run(input: &[u8]) -> Answer {
+    answer(|out, cap| unsafe { submission::run(input.as_ptr(), input.len(), out, cap) })
+}
+
+/// Whatever `parse` returned, which is only ever passed on to `solve`
+#[cfg(feature = "split")]
+pub struct Parsed(*mut c_void);
+
+#[cfg(feature = "split")]
+pub fn parse(input: &[u8]) -> Parsed {
+    Parsed(unsafe { submission::parse(input.as_ptr(), input.len()) })
+}
+
+#[cfg(feature = "split")]
+pub fn solve(parsed: Parsed) -> Answer {
+    answer(|out, cap| unsafe { submission::solve(parsed.0, out, cap) })
+}
+
+#[cfg(feature = "parts")]
+pub fn part1(input: &[u8]) -> Answer {
+    answer(|out, cap| unsafe { submission::part1(input.as_ptr(), input.len(), out, cap) })
+}
+
+#[cfg(feature = "parts")]
+pub fn part2(input: &[u8]) -> Answer {
+    answer(|out, cap| unsafe { submission::part2(input.as_ptr(), input.len(), out, cap) })
+}
@@ -0,0 +1,18 @@
+#include <stddef.h>
+#include <stdlib.h>
+
+// Takes the input and writes the answer as text into `out`, returning its length, which may
+// be at most `cap`. Define `parse` and `solve` instead to have them benchmarked separately,
+// or `part1` and `part2` to solve both parts at once:
+//
+//     void *parse(const unsigned char *input, size_t len);
+//     size_t solve(void *parsed, char *out, size_t cap);
+//     size_t part1(const unsigned char *input, size_t len, char *out, size_t cap);
+//     size_t part2(const unsigned char *input, size_t len, char *out, size_t cap);
+//
+// C++ submissions declare them `extern "C"`, Zig submissions `export`.
+size_t run(const unsigned char *input, size_t len, char *out, size_t cap) {
+    (void)input, (void)len, (void)out, (void)cap;
+    // Something has gone horribly wrong.
+    abort();
+}
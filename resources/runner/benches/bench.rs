@@ -1,43 +1,138 @@
-use iai_callgrind::{library_benchmark, library_benchmark_group, main, LibraryBenchmarkConfig};
+use iai_callgrind::{
+    library_benchmark, library_benchmark_group, main, LibraryBenchmarkConfig, Tool, ValgrindTool,
+};
 
 use std::env;
+use std::fmt::Display;
+use std::fs::OpenOptions;
 use std::hint::black_box;
+use std::io::Write;
 
-pub trait IntoInput<T: Copy> {
-    fn into_input(self) -> T;
+use common::{read_input, IntoInput, INPUT, INPUTS_DIR};
+
+mod common;
+
+/// Where answers are recorded for the worker
+const RESULTS_PATH: &str = "RESULTS_PATH";
+
+/// Records the answer along with the benchmark and input it belongs to, once for every
+/// valgrind tool the benchmark runs under. Instruction counts aren't recorded here as this
+/// runs inside the measured process, where the submission could tamper with them. The worker
+/// reads those from iai-callgrind's summary instead.
+fn record_result(bench: &str, res: impl Display) {
+    // Answers are line delimited, so multi-line answers are kept on one line
+    let res = res.to_string();
+    let record = format!(
+        "{bench}\t{}\t{}\n",
+        INPUT.get().unwrap(),
+        res.trim_end().replace('\n', "\\n")
+    );
+    let mut results = OpenOptions::new()
+        .append(true)
+        .open(env::var_os(RESULTS_PATH).unwrap())
+        .unwrap();
+    results.write_all(record.as_bytes()).unwrap();
 }
 
-impl IntoInput<&[u8]> for Vec<u8> {
-    fn into_input(self) -> &'static [u8] {
-        self.leak()
-    }
+/// A call to `solve` with its input already parsed
+#[cfg(feature = "split")]
+pub trait Solve {
+    type Answer: Display;
+
+    fn solve(self) -> Self::Answer;
 }
 
-impl IntoInput<&str> for Vec<u8> {
-    fn into_input(self) -> &'static str {
-        unsafe { std::str::from_utf8_unchecked(self.leak()) }
+#[cfg(feature = "split")]
+impl<F: FnOnce() -> R, R: Display> Solve for F {
+    type Answer = R;
+
+    fn solve(self) -> R {
+        self()
     }
 }
 
-fn read_input(name: &str) -> Vec<u8> {
-    env::var(name).unwrap().into_bytes()
+/// Parses the input ahead of time so that only `solve` is measured
+#[cfg(feature = "split")]
+fn parse_input(name: &str) -> impl Solve {
+    let parsed = runner::parse(read_input(name).into_input());
+    move || runner::solve(parsed)
+}
+
+#[cfg(not(any(feature = "split", feature = "parts")))]
+fn record_run(res: impl Display) {
+    record_result("run", res);
 }
 
-fn print_result(res: i64) {
-    println!("Solution: {res}");
+#[cfg(feature = "split")]
+fn record_solve(res: impl Display) {
+    record_result("solve", res);
 }
 
+#[cfg(feature = "parts")]
+fn record_part1(res: impl Display) {
+    record_result("part1", res);
+}
+
+#[cfg(feature = "parts")]
+fn record_part2(res: impl Display) {
+    record_result("part2", res);
+}
+
+#[cfg(not(any(feature = "split", feature = "parts")))]
 #[library_benchmark]
-#[benches::run(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = read_input, teardown = print_result)]
-fn bench_run(input: Vec<u8>) -> i64 {
+#[benches::run(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = read_input, teardown = record_run)]
+fn bench_run(input: Vec<u8>) -> impl Display {
     black_box(runner::run(input.into_input()))
 }
 
+#[cfg(feature = "split")]
+#[library_benchmark]
+#[benches::parse(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = read_input, teardown = drop)]
+fn bench_parse(input: Vec<u8>) -> impl Sized {
+    black_box(runner::parse(input.into_input()))
+}
+
+#[cfg(feature = "split")]
+#[library_benchmark]
+#[benches::solve(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = parse_input, teardown = record_solve)]
+fn bench_solve(solve: impl Solve) -> impl Display {
+    black_box(solve.solve())
+}
+
+#[cfg(feature = "parts")]
+#[library_benchmark]
+#[benches::part1(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = read_input, teardown = record_part1)]
+fn bench_part1(input: Vec<u8>) -> impl Display {
+    black_box(runner::part1(input.into_input()))
+}
+
+#[cfg(feature = "parts")]
+#[library_benchmark]
+#[benches::part2(args = ["INPUT_1", "INPUT_2", "INPUT_3"], setup = read_input, teardown = record_part2)]
+fn bench_part2(input: Vec<u8>) -> impl Display {
+    black_box(runner::part2(input.into_input()))
+}
+
+#[cfg(not(any(feature = "split", feature = "parts")))]
 library_benchmark_group!(
     name = group;
     benchmarks = bench_run
 );
+#[cfg(feature = "split")]
+library_benchmark_group!(
+    name = group;
+    benchmarks = bench_parse, bench_solve
+);
+#[cfg(feature = "parts")]
+library_benchmark_group!(
+    name = group;
+    benchmarks = bench_part1, bench_part2
+);
+// The worker passes the callgrind args admins configure on the command line, which take
+// precedence over these. DHAT runs every benchmark again to measure its peak heap size.
 main!(
-    config = LibraryBenchmarkConfig::with_callgrind_args(["--cache-sim=no"]);
+    config = LibraryBenchmarkConfig::with_callgrind_args(["--cache-sim=no"])
+        .pass_through_envs([INPUTS_DIR, RESULTS_PATH])
+        .tool(Tool::new(ValgrindTool::DHAT));
     library_benchmark_groups = group
 );
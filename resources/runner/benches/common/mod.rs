@@ -0,0 +1,45 @@
+//! What the instruction counting and wall-clock harnesses share
+
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The directory the worker stages the inputs in, one file per input
+pub const INPUTS_DIR: &str = "INPUTS_DIR";
+
+/// The input being benchmarked, each input runs in a separate process
+pub static INPUT: OnceLock<String> = OnceLock::new();
+
+/// Converts the raw input into whatever the submission takes, picked by inference from the
+/// signature of the function it is passed to. Answers only need to implement `Display`.
+pub trait IntoInput<T: Copy> {
+    fn into_input(self) -> T;
+}
+
+impl IntoInput<&[u8]> for Vec<u8> {
+    fn into_input(self) -> &'static [u8] {
+        self.leak()
+    }
+}
+
+impl IntoInput<&str> for Vec<u8> {
+    fn into_input(self) -> &'static str {
+        // Inputs are arbitrary bytes, so this can't be assumed
+        String::from_utf8(self)
+            .expect("the input is not valid UTF-8, take `&[u8]` instead")
+            .leak()
+    }
+}
+
+pub fn read_input(name: &str) -> Vec<u8> {
+    INPUT.set(name.to_owned()).unwrap();
+    // Lets the worker tell which input a panic happened on
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        eprintln!("Panicked while running {}", INPUT.get().unwrap());
+        hook(info);
+    }));
+    fs::read(Path::new(&env::var_os(INPUTS_DIR).unwrap()).join(name)).unwrap()
+}
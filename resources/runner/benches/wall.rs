@@ -0,0 +1,125 @@
+//! Times the submission natively, as a counterpart to the instruction counts. Unlike
+//! instructions, wall-clock time rewards using the cache and SIMD the way real hardware does,
+//! at the cost of some noise.
+//!
+//! Like the instruction counting harness, each input runs in a separate process. The worker
+//! names the input as the only argument.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::hint::black_box;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use common::{read_input, IntoInput, INPUT};
+
+mod common;
+
+/// Where timings are recorded for the worker
+const TIMINGS_PATH: &str = "TIMINGS_PATH";
+/// How long to run each function before timing it, in milliseconds
+const WARMUP_MS: &str = "WARMUP_MS";
+/// How many calls of each function to time
+const SAMPLES: &str = "SAMPLES";
+
+struct Config {
+    warmup: Duration,
+    samples: usize,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let var = |name| env::var(name).unwrap().parse::<u64>().unwrap();
+        Self {
+            warmup: Duration::from_millis(var(WARMUP_MS)),
+            samples: var(SAMPLES).max(1) as usize,
+        }
+    }
+}
+
+/// Times `routine` on what `setup` returns, which is prepared afresh for every call. Only
+/// `routine` is timed, dropping what it returns is not.
+fn sample<S, R>(
+    config: &Config,
+    mut setup: impl FnMut() -> S,
+    mut routine: impl FnMut(S) -> R,
+) -> Vec<Duration> {
+    // Always at least once, so that slow submissions aren't timed cold
+    let warm = Instant::now() + config.warmup;
+    loop {
+        black_box(routine(black_box(setup())));
+        if Instant::now() >= warm {
+            break;
+        }
+    }
+
+    (0..config.samples)
+        .map(|_| {
+            let input = black_box(setup());
+            let start = Instant::now();
+            let res = routine(input);
+            let elapsed = start.elapsed();
+            drop(black_box(res));
+            elapsed
+        })
+        .collect()
+}
+
+/// Rejects outliers beyond Tukey's fences, then records the median along with the
+/// interquartile range as the spread. Timings are recorded from inside the timed process,
+/// the worker can only check that they are complete.
+fn record_timing(bench: &str, mut samples: Vec<Duration>) {
+    samples.sort_unstable();
+    let quartiles = |samples: &[Duration]| {
+        let len = samples.len();
+        (samples[len / 4], samples[len / 2], samples[len * 3 / 4])
+    };
+    let (q1, _, q3) = quartiles(&samples);
+    let fence = (q3 - q1) * 3 / 2;
+    samples.retain(|&s| s + fence >= q1 && s <= q3 + fence);
+    let (q1, median, q3) = quartiles(&samples);
+
+    let record = format!(
+        "{bench}\t{}\t{}\t{}\t{}\n",
+        INPUT.get().unwrap(),
+        median.as_nanos(),
+        (q3 - q1).as_nanos(),
+        samples.len()
+    );
+    let mut timings = OpenOptions::new()
+        .append(true)
+        .open(env::var_os(TIMINGS_PATH).unwrap())
+        .unwrap();
+    timings.write_all(record.as_bytes()).unwrap();
+}
+
+fn main() {
+    let name = env::args().nth(1).expect("no input given");
+    let config = Config::from_env();
+    let input = read_input(&name);
+
+    #[cfg(not(any(feature = "split", feature = "parts")))]
+    {
+        let input = input.into_input();
+        record_timing("run", sample(&config, || (), |()| runner::run(input)));
+    }
+
+    #[cfg(feature = "split")]
+    {
+        let input = input.into_input();
+        record_timing("parse", sample(&config, || (), |()| runner::parse(input)));
+        // Parsed again for every call, as `solve` takes what `parse` returned by value
+        record_timing(
+            "solve",
+            sample(&config, || runner::parse(input), runner::solve),
+        );
+    }
+
+    #[cfg(feature = "parts")]
+    {
+        // Each part may take the input in a different form
+        let (input1, input2) = (input.clone().into_input(), input.into_input());
+        record_timing("part1", sample(&config, || (), |()| runner::part1(input1)));
+        record_timing("part2", sample(&config, || (), |()| runner::part2(input2)));
+    }
+}
@@ -1,3 +1,8 @@
-pub fn run(_: &str) -> i64 {
+use std::fmt::Display;
+
+// Takes the input as either `&str` or `&[u8]` and returns anything that implements
+// `Display`, such as an integer or a `String`. Define `parse` and `solve` instead to
+// have them benchmarked separately, or `part1` and `part2` to solve both parts at once.
+pub fn run(_: &str) -> impl Display {
     panic!("Something has gone horribly wrong.")
 }
@@ -0,0 +1,122 @@
+//! Reading what the build produced, without any tools beyond the worker itself
+
+/// Counts the object files in a static library. An rlib holds one for each codegen unit.
+pub fn object_files(archive: &[u8]) -> Result<u32, String> {
+    let mut rest = archive
+        .strip_prefix(b"!<arch>\n")
+        .ok_or("The library is not an archive")?;
+    // Names too long for a header are kept in a member of their own
+    let mut long_names: &[u8] = &[];
+    let mut count = 0;
+    while !rest.is_empty() {
+        if rest.len() < 60 {
+            return Err("Truncated archive member header".into());
+        }
+        let (header, body) = rest.split_at(60);
+        let field = |range: std::ops::Range<usize>| {
+            std::str::from_utf8(&header[range])
+                .map(str::trim_end)
+                .map_err(|_| "Archive member header is not UTF-8".to_owned())
+        };
+        let size = field(48..58)?
+            .parse::<usize>()
+            .map_err(|_| "Invalid archive member size")?;
+        let data = body.get(..size).ok_or("Truncated archive member")?;
+
+        let name = field(0..16)?;
+        let name = if name == "//" {
+            long_names = data;
+            None
+        } else if let Some(offset) = name.strip_prefix('/').and_then(|n| n.parse().ok()) {
+            let name = long_names
+                .get(offset..)
+                .ok_or("Invalid archive member name")?;
+            let end = name.iter().position(|&b| b == b'\n').unwrap_or(name.len());
+            Some(&name[..end])
+        } else {
+            Some(name.as_bytes())
+        };
+        // GNU archives end names with a slash
+        if name.is_some_and(|name| name.strip_suffix(b"/").unwrap_or(name).ends_with(b".o")) {
+            count += 1;
+        }
+
+        // Members are aligned to two bytes
+        rest = body.get(size + size % 2..).unwrap_or_default();
+    }
+    Ok(count)
+}
+
+/// The size of the `.text` section of a 64-bit little endian ELF executable
+pub fn text_size(elf: &[u8]) -> Result<u64, String> {
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err("The executable is not a 64-bit little endian ELF".into());
+    }
+    let read = |offset: usize, len: usize| {
+        elf.get(offset..offset + len)
+            .map(|bytes| bytes.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b)))
+            .ok_or_else(|| format!("The executable is truncated at {offset}"))
+    };
+    let headers = read(0x28, 8)? as usize;
+    let header_size = read(0x3a, 2)? as usize;
+    let count = read(0x3c, 2)? as usize;
+    let names = read(headers + read(0x3e, 2)? as usize * header_size + 0x18, 8)? as usize;
+
+    for i in 0..count {
+        let header = headers + i * header_size;
+        let name = names + read(header, 4)? as usize;
+        if elf.get(name..name + 6) == Some(b".text\0") {
+            return read(header + 0x20, 8);
+        }
+    }
+    Err("The executable has no .text section".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "{name:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        )
+        .into_bytes();
+        member.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    #[test]
+    fn object_files_are_counted() {
+        let long_names = b"runner-0123abcd.runner.1a2b-cgu.0.rcgu.o/\nrunner-0123abcd.runner.1a2b-cgu.1.rcgu.o/\n";
+        let mut rlib = b"!<arch>\n".to_vec();
+        rlib.extend(member("/", &[0; 5]));
+        rlib.extend(member("//", long_names));
+        rlib.extend(member("lib.rmeta/", b"meta"));
+        rlib.extend(member("/0", b"odd"));
+        rlib.extend(member("/42", b"object"));
+        assert_eq!(object_files(&rlib), Ok(2));
+    }
+
+    #[test]
+    fn other_files_are_not_archives() {
+        assert!(object_files(b"\x7fELF").is_err());
+        let mut rlib = b"!<arch>\n".to_vec();
+        rlib.extend(&member("lib.rmeta/", b"meta")[..62]);
+        assert!(object_files(&rlib).is_err());
+    }
+
+    #[test]
+    fn text_is_found_in_this_executable() {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert!(text_size(&exe).unwrap() > 0);
+        assert!(text_size(b"!<arch>\n").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Authentication for workers reached over a socket.
+//!
+//! The bot and its remote workers share a secret. Every connection starts with a random
+//! challenge from each side, the worker's in its [`Hello`](crate::Hello) and the bot's in
+//! its [`Greeting`](crate::Greeting). Every request and every message from the worker on
+//! it carries an HMAC-SHA256 over both challenges and the encoded message. Neither side can
+//! be impersonated without the secret, and messages can't be replayed on another
+//! connection, whichever side started it.
+
+use hmac::{Hmac, Mac as _};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub type Mac = [u8; 32];
+pub type Challenge = [u8; 32];
+
+/// Keeps a MAC over a request from passing for one over a message from the worker
+#[derive(Clone, Copy)]
+enum Direction {
+    Request,
+    Message,
+}
+
+/// The challenges of both sides of a connection, which every MAC on it covers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Session {
+    pub worker: Challenge,
+    pub bot: Challenge,
+}
+
+#[derive(Clone)]
+pub struct Key(Hmac<Sha256>);
+
+impl Key {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(Hmac::new_from_slice(secret).expect("HMAC takes keys of any length"))
+    }
+
+    pub fn sign_request<T: Serialize>(&self, session: &Session, req: &T) -> Mac {
+        self.mac(Direction::Request, session, req)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    pub fn sign_message<T: Serialize>(&self, session: &Session, msg: &T) -> Mac {
+        self.mac(Direction::Message, session, msg)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    pub fn verify_request<T: Serialize>(&self, session: &Session, req: &T, mac: &Mac) -> bool {
+        self.mac(Direction::Request, session, req)
+            .verify_slice(mac)
+            .is_ok()
+    }
+
+    pub fn verify_message<T: Serialize>(&self, session: &Session, msg: &T, mac: &Mac) -> bool {
+        self.mac(Direction::Message, session, msg)
+            .verify_slice(mac)
+            .is_ok()
+    }
+
+    fn mac<T: Serialize>(&self, direction: Direction, session: &Session, msg: &T) -> Hmac<Sha256> {
+        let mut mac = self.0.clone();
+        mac.update(&[direction as u8]);
+        mac.update(&session.worker);
+        mac.update(&session.bot);
+        // Encoding the messages we have in memory can't fail
+        mac.update(&bincode::serialize(msg).expect("failed to encode message"));
+        mac
+    }
+}
+
+/// A fresh challenge for a new connection
+pub fn challenge() -> Challenge {
+    let mut challenge = Challenge::default();
+    getrandom::getrandom(&mut challenge).expect("no randomness available");
+    challenge
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Outcome, Response};
+
+    fn message() -> Message {
+        Message::Response(Response {
+            id: 1,
+            outcome: Outcome::CompileTimeout,
+        })
+    }
+
+    fn session() -> Session {
+        Session {
+            worker: challenge(),
+            bot: challenge(),
+        }
+    }
+
+    #[test]
+    fn signed_messages_verify() {
+        let key = Key::new(b"hunter2");
+        let session = session();
+        let mac = key.sign_message(&session, &message());
+        assert!(key.verify_message(&session, &message(), &mac));
+    }
+
+    #[test]
+    fn other_secrets_are_rejected() {
+        let session = session();
+        let mac = Key::new(b"hunter2").sign_message(&session, &message());
+        assert!(!Key::new(b"hunter3").verify_message(&session, &message(), &mac));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let key = Key::new(b"hunter2");
+        let session = session();
+        let mac = key.sign_message(&session, &message());
+        let tampered = Message::Response(Response {
+            id: 2,
+            outcome: Outcome::CompileTimeout,
+        });
+        assert!(!key.verify_message(&session, &tampered, &mac));
+    }
+
+    #[test]
+    fn replays_on_other_connections_are_rejected() {
+        let key = Key::new(b"hunter2");
+        let session = session();
+        let mac = key.sign_message(&session, &message());
+        // Whichever side's challenge differs
+        let other_bot = Session {
+            bot: challenge(),
+            ..session
+        };
+        let other_worker = Session {
+            worker: challenge(),
+            ..session
+        };
+        assert!(!key.verify_message(&other_bot, &message(), &mac));
+        assert!(!key.verify_message(&other_worker, &message(), &mac));
+    }
+
+    #[test]
+    fn requests_do_not_pass_for_messages() {
+        let key = Key::new(b"hunter2");
+        let session = session();
+        let mac = key.sign_request(&session, &message());
+        assert!(!key.verify_message(&session, &message(), &mac));
+    }
+}
@@ -0,0 +1,1052 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::{
+    fs::{create_dir_all, read, read_to_string, write},
+    process::Command,
+    select,
+    time::{sleep, timeout},
+};
+use worker::{
+    Build, InputResult, Outcome, PartResult, Progress, Request, Shape, Timing, WallClock,
+};
+
+use crate::{
+    Error, artifacts,
+    job::{JobDir, User, run_as},
+    summary::{self, Measured},
+    template::{NATIVE_LIBRARY, Runner, Template, template},
+    workspace::Workspace,
+};
+
+/// How often to check how far along a benchmark run is
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A line of `cargo --message-format=json` output
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    #[serde(default)]
+    filenames: Vec<PathBuf>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+}
+
+/// The benchmarks built for a submission
+struct Executables {
+    /// Counts instructions under callgrind
+    bench: PathBuf,
+    /// Times the submission natively, only built when asked to
+    wall: Option<PathBuf>,
+}
+
+/// Builds and benchmarks the submission in `req` using the copy of the runner crate for its
+/// language in `workspace`.
+///
+/// Building and running are separate steps so that the inputs are never visible while the
+/// submission is compiled. Otherwise it could read them with `include_bytes!` or similar and
+/// compute the answer at compile time.
+pub async fn benchmark(
+    workspace: &Workspace,
+    req: &Request,
+    progress: impl Fn(Progress),
+) -> Result<Outcome, Error> {
+    let template = template(req.language);
+    let source = workspace.dir().join(template.source);
+    if let Some(dir) = source.parent() {
+        create_dir_all(dir).await?;
+    }
+    write(source, &req.code).await?;
+
+    let shape = Shape::detect(req.language, &req.code);
+    // Submissions solving both parts know which part each result is for
+    let parts = match (shape.solves_both(), req.part) {
+        (true, _) => vec![1, 2],
+        (false, Some(part)) => vec![part],
+        (false, None) => return Err("No part given for a single part submission".into()),
+    };
+
+    progress(Progress::Compiling);
+    let timed = req.wall_clock.is_some();
+    let (executables, build) = match build(
+        workspace,
+        &template,
+        shape,
+        timed,
+        req.limits.compile_timeout,
+    )
+    .await?
+    {
+        Ok((executables, build, warnings)) => {
+            progress(Progress::Compiled { warnings });
+            (executables, build)
+        }
+        Err(outcome) => return Ok(outcome),
+    };
+
+    run(workspace, &executables, build, shape, &parts, req, progress).await
+}
+
+/// Compiles the benchmarks, returning the paths to their executables and what the build
+/// produced along with any warnings, or why they could not be built
+async fn build(
+    workspace: &Workspace,
+    template: &Template,
+    shape: Shape,
+    timed: bool,
+    limit: Duration,
+) -> Result<Result<(Executables, Build, String), Outcome>, Error> {
+    // Languages other than Rust are compiled before cargo links them into the harness
+    let started = Instant::now();
+    for args in template.build {
+        let mut cmd = Command::new(args[0]);
+        run_as(&mut cmd, workspace.builder())
+            .args(&args[1..])
+            .current_dir(workspace.dir())
+            .env("TMPDIR", workspace.dir());
+        let limit = limit.saturating_sub(started.elapsed());
+        let Some(output) = output_within(&mut cmd, workspace.builder(), limit).await? else {
+            return Ok(Err(Outcome::CompileTimeout));
+        };
+        if !output.status.success() {
+            let diagnostics = String::from_utf8_lossy(&output.stderr).into();
+            return Ok(Err(Outcome::CompileError(diagnostics)));
+        }
+    }
+
+    let mut cmd = Command::new("cargo");
+    run_as(&mut cmd, workspace.builder()).args([
+        "bench",
+        "--bench",
+        "bench",
+        "--no-run",
+        "--quiet",
+        "--offline",
+        "--message-format=json-render-diagnostics",
+    ]);
+    if timed {
+        cmd.args(["--bench", "wall"]);
+    }
+    if let Some(feature) = shape.feature() {
+        cmd.args(["--features", feature]);
+    }
+    // Temporary files go where only builds can see them
+    cmd.current_dir(workspace.dir())
+        .env("TMPDIR", workspace.dir())
+        .env("CARGO_TARGET_DIR", workspace.target_dir())
+        .env("SUBMISSION_LINK", template.link);
+    let limit = limit.saturating_sub(started.elapsed());
+    let Some(output) = output_within(&mut cmd, workspace.builder(), limit).await? else {
+        return Ok(Err(Outcome::CompileTimeout));
+    };
+    let compile_time = started.elapsed();
+    if !output.status.success() {
+        let diagnostics = String::from_utf8_lossy(&output.stderr).into();
+        return Ok(Err(Outcome::CompileError(diagnostics)));
+    }
+
+    let artifacts = |name: &str| {
+        output
+            .stdout
+            .split(|&b| b == b'\n')
+            .filter_map(|line| serde_json::from_slice::<CargoMessage>(line).ok())
+            .filter(|msg| msg.reason == "compiler-artifact")
+            .find(|msg| msg.target.as_ref().is_some_and(|t| t.name == name))
+            .ok_or_else(|| format!("cargo did not report building {name}"))
+    };
+    let executable = |name: &str| {
+        artifacts(name)?
+            .executable
+            .ok_or_else(|| format!("cargo did not report the {name} executable"))
+    };
+    let executables = Executables {
+        bench: executable("bench")?,
+        wall: timed.then(|| executable("wall")).transpose()?,
+    };
+
+    // Only the objects of the submission, not the runner crate that calls into it
+    let library = match template.runner {
+        Runner::Rust => artifacts("runner")?
+            .filenames
+            .into_iter()
+            .find(|path| path.extension().is_some_and(|ext| ext == "rlib"))
+            .ok_or("cargo did not report the runner library")?,
+        Runner::Native => workspace.dir().join(NATIVE_LIBRARY),
+    };
+    let build = Build {
+        compile_time,
+        codegen_units: artifacts::object_files(&read(library).await?)?,
+        text_size: artifacts::text_size(&read(&executables.bench).await?)?,
+    };
+    // Diagnostics are rendered to stderr, which only has warnings left when the build passed
+    let warnings = String::from_utf8_lossy(&output.stderr).trim().into();
+    Ok(Ok((executables, build, warnings)))
+}
+
+/// Runs the already built benchmark executables against the inputs
+async fn run(
+    workspace: &Workspace,
+    executables: &Executables,
+    build: Build,
+    shape: Shape,
+    parts: &[u8],
+    req: &Request,
+    progress: impl Fn(Progress),
+) -> Result<Outcome, Error> {
+    let Request {
+        id, inputs, limits, ..
+    } = req;
+    let count = inputs.len();
+    let started = Instant::now();
+
+    let job_dir = JobDir::create(*id, workspace.submitter()).await?;
+    let dir = job_dir.path();
+    let results_path = job_dir.writable_file("results").await?;
+    let home = dir.join("iai");
+
+    let inputs_dir = job_dir.stage_inputs(inputs).await?;
+
+    let mut cmd = Command::new(&executables.bench);
+    run_as(&mut cmd, job_dir.user());
+    if !req.callgrind_args.is_empty() {
+        cmd.arg(format!("--callgrind-args={}", req.callgrind_args.join(" ")));
+    }
+    cmd.arg("--save-summary=json")
+        .arg(format!("--home={}", home.display()))
+        .env("INPUTS_DIR", &inputs_dir)
+        .env("RESULTS_PATH", &results_path)
+        .env("TMPDIR", dir)
+        .current_dir(workspace.dir());
+    // Each benchmark saves a summary once it's done, so they show how far along the run is.
+    // They are copied out of reach of the submission right away.
+    let copies = job_dir.private();
+    let total = shape.benchmarks() * count;
+    let watch_progress = async {
+        let mut current = 0;
+        loop {
+            let done = summary::copy_summaries(&home, copies);
+            if done >= current && done < total {
+                current = done + 1;
+                progress(Progress::Benchmarking { current, total });
+            }
+            sleep(PROGRESS_INTERVAL).await;
+        }
+    };
+    let oom_kills_before = oom_kills();
+    let output = select! {
+        output = output_within(&mut cmd, job_dir.user(), limits.run_timeout) => output?,
+        () = watch_progress => unreachable!("progress is watched until the run ends"),
+    };
+    // Anything the submission left running could still change what it saved
+    job_dir.kill_all();
+
+    let records = read_to_string(&results_path).await?;
+    let outcome = match output {
+        None => Outcome::RunTimeout,
+        Some(output) if !output.status.success() => {
+            classify_failure(&output, oom_kills() > oom_kills_before)
+        }
+        Some(_) => {
+            let answers = summary::parse_answers(&records, count, summary::VALGRIND_RUNS);
+            let results = answers.and_then(|answers| {
+                summary::copy_summaries(&home, copies);
+                summary::check_copies(&home, copies)?;
+                let measured = summary::read_summaries(copies, count)?;
+                collect_results(answers, measured, shape, parts)
+            });
+            match results {
+                Ok(parts) => match check_budget(&parts, limits.max_instructions) {
+                    Some(exceeded) => exceeded,
+                    None => Outcome::Success { parts, build },
+                },
+                Err(e) => Outcome::InvalidResults(e),
+            }
+        }
+    };
+
+    // Only what ran fine under callgrind is worth timing, in whatever time is left
+    let outcome = match (outcome, &executables.wall, req.wall_clock) {
+        (Outcome::Success { parts, build }, Some(wall), Some(wall_clock)) => {
+            let limit = limits.run_timeout.saturating_sub(started.elapsed());
+            let crate_dir = workspace.dir();
+            match time(
+                crate_dir, wall, wall_clock, &job_dir, count, limit, &progress,
+            )
+            .await?
+            {
+                Ok(timings) => add_timings(parts, timings, shape).map_or_else(
+                    Outcome::InvalidResults,
+                    |parts| Outcome::Success { parts, build },
+                ),
+                Err(outcome) => outcome,
+            }
+        }
+        (outcome, _, _) => outcome,
+    };
+
+    Ok(outcome)
+}
+
+/// Times every input staged in `job_dir` with the wall-clock benchmark, one process per
+/// input like under callgrind
+async fn time(
+    crate_dir: &Path,
+    wall: &Path,
+    wall_clock: WallClock,
+    job_dir: &JobDir,
+    count: usize,
+    limit: Duration,
+    progress: impl Fn(Progress),
+) -> Result<Result<HashMap<String, Vec<Timing>>, Outcome>, Error> {
+    let timings_path = job_dir.writable_file("timings").await?;
+    let deadline = Instant::now() + limit;
+    for i in 1..=count {
+        progress(Progress::Timing {
+            current: i,
+            total: count,
+        });
+        let mut cmd = Command::new(wall);
+        run_as(&mut cmd, job_dir.user())
+            .arg(format!("INPUT_{i}"))
+            .env("INPUTS_DIR", job_dir.path().join("inputs"))
+            .env("TMPDIR", job_dir.path())
+            .env("TIMINGS_PATH", &timings_path)
+            .env("WARMUP_MS", wall_clock.warmup.as_millis().to_string())
+            .env("SAMPLES", wall_clock.samples.to_string())
+            .current_dir(crate_dir);
+        let limit = deadline.saturating_duration_since(Instant::now());
+        let oom_kills_before = oom_kills();
+        let output = output_within(&mut cmd, job_dir.user(), limit).await?;
+        job_dir.kill_all();
+        match output {
+            None => return Ok(Err(Outcome::RunTimeout)),
+            Some(output) if !output.status.success() => {
+                return Ok(Err(classify_failure(
+                    &output,
+                    oom_kills() > oom_kills_before,
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+
+    let records = read_to_string(&timings_path).await?;
+    Ok(summary::parse_timings(&records, count).map_err(Outcome::InvalidResults))
+}
+
+/// Runs the command, which runs as `user`, to completion, or kills it along with everything
+/// it started once `limit` has passed, returning `None`
+async fn output_within(
+    cmd: &mut Command,
+    user: Option<User>,
+    limit: Duration,
+) -> Result<Option<Output>, Error> {
+    let child = cmd
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    // Also covers the request being abandoned, such as when the bot hangs up on a worker
+    // that outlives the connection
+    let _group = child.id().map(|pgid| ProcessGroup { pgid, user });
+    Ok(timeout(limit, child.wait_with_output())
+        .await
+        .ok()
+        .transpose()?)
+}
+
+/// Kills every process in a group when dropped
+struct ProcessGroup {
+    pgid: u32,
+    /// Who they run as, if not the worker itself
+    user: Option<User>,
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        match self.user {
+            Some(user) => {
+                if let Err(e) = user.kill_group(self.pgid) {
+                    eprintln!("Failed to kill process group {}: {e}", self.pgid);
+                }
+            }
+            // SAFETY: kill has no memory safety requirements
+            None => unsafe {
+                libc::kill(-(self.pgid as libc::pid_t), libc::SIGKILL);
+            },
+        }
+    }
+}
+
+/// Why a successful run fails after all, if any benchmark spent more instructions than
+/// allowed
+fn check_budget(results: &[PartResult], max_instructions: Option<u64>) -> Option<Outcome> {
+    let max = max_instructions?;
+    results.iter().find_map(|part| {
+        part.inputs
+            .iter()
+            .position(|input| input.instructions > max)
+            .map(|input| Outcome::InstructionLimit {
+                input,
+                instructions: part.inputs[input].instructions,
+            })
+    })
+}
+
+/// Pairs answers with what valgrind measured of the benchmarks expected for the submission
+fn collect_results(
+    mut answers: HashMap<String, Vec<String>>,
+    mut measured: HashMap<String, Vec<Measured>>,
+    shape: Shape,
+    parts: &[u8],
+) -> Result<Vec<PartResult>, String> {
+    let mut take_answers = |bench: &str| {
+        answers
+            .remove(bench)
+            .ok_or_else(|| format!("No results recorded for {bench}"))
+    };
+    let mut take_measured = |bench: &str| {
+        measured
+            .remove(bench)
+            .ok_or_else(|| format!("No summaries for {bench}"))
+    };
+    // Benchmarks that measure the whole of a part in one go
+    let mut whole = |bench: &str, part: u8| {
+        let inputs = take_answers(bench)?
+            .into_iter()
+            .zip(take_measured(bench)?)
+            .map(|(answer, Measured { metrics, peak_heap })| InputResult {
+                answer,
+                instructions: metrics[summary::INSTRUCTIONS],
+                parse_instructions: None,
+                solve_instructions: None,
+                metrics,
+                peak_heap,
+                timing: None,
+            })
+            .collect();
+        Ok::<_, String>(PartResult { part, inputs })
+    };
+    let results = match shape {
+        Shape::Run => vec![whole("run", parts[0])?],
+        Shape::Parts => vec![whole("part1", parts[0])?, whole("part2", parts[1])?],
+        Shape::Split => {
+            let parse = take_measured("parse")?;
+            let solve = take_measured("solve")?;
+            let inputs = take_answers("solve")?
+                .into_iter()
+                .zip(parse.into_iter().zip(solve))
+                .map(|(answer, (parse, solve))| {
+                    let parse_instructions = parse.metrics[summary::INSTRUCTIONS];
+                    let solve_instructions = solve.metrics[summary::INSTRUCTIONS];
+                    let mut metrics = parse.metrics;
+                    for (event, value) in solve.metrics {
+                        *metrics.entry(event).or_default() += value;
+                    }
+                    InputResult {
+                        answer,
+                        instructions: parse_instructions + solve_instructions,
+                        parse_instructions: Some(parse_instructions),
+                        solve_instructions: Some(solve_instructions),
+                        metrics,
+                        // What `parse` returned is still held while solving
+                        peak_heap: parse.peak_heap.max(solve.peak_heap),
+                        timing: None,
+                    }
+                })
+                .collect();
+            vec![PartResult {
+                part: parts[0],
+                inputs,
+            }]
+        }
+    };
+
+    if let Some(bench) = answers.keys().next() {
+        return Err(format!("Unexpected results recorded for {bench}"));
+    }
+    if let Some(bench) = measured.keys().next() {
+        return Err(format!("Unexpected summaries for {bench}"));
+    }
+    Ok(results)
+}
+
+/// Adds the timings of the benchmarks expected for the submission to its results
+fn add_timings(
+    mut results: Vec<PartResult>,
+    mut timings: HashMap<String, Vec<Timing>>,
+    shape: Shape,
+) -> Result<Vec<PartResult>, String> {
+    let mut take_timings = |bench: &str| {
+        timings
+            .remove(bench)
+            .ok_or_else(|| format!("No timings recorded for {bench}"))
+    };
+    let timings_per_part = match shape {
+        Shape::Run => vec![take_timings("run")?],
+        Shape::Parts => vec![take_timings("part1")?, take_timings("part2")?],
+        Shape::Split => {
+            let parse = take_timings("parse")?;
+            let solve = take_timings("solve")?;
+            vec![
+                parse
+                    .into_iter()
+                    .zip(solve)
+                    .map(|(parse, solve)| Timing {
+                        median: parse.median + solve.median,
+                        spread: parse.spread + solve.spread,
+                        samples: parse.samples.min(solve.samples),
+                    })
+                    .collect(),
+            ]
+        }
+    };
+    for (part, part_timings) in results.iter_mut().zip(timings_per_part) {
+        for (input, timing) in part.inputs.iter_mut().zip(part_timings) {
+            input.timing = Some(timing);
+        }
+    }
+
+    if let Some(bench) = timings.keys().next() {
+        return Err(format!("Unexpected timings for {bench}"));
+    }
+    Ok(results)
+}
+
+/// Works out why a benchmark failed from what the worker saw of it. Only the OOM killer is
+/// told apart, anything else that makes it fail is up to the submission. Its stderr only
+/// serves as the message, as the submission can write anything there.
+fn classify_failure(output: &Output, oom_killed: bool) -> Outcome {
+    if oom_killed {
+        return Outcome::MemoryLimit;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // The harness names the input before the panic message
+    let input = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("Panicked while running INPUT_"))
+        .find_map(|n| n.trim().parse::<usize>().ok())
+        .map_or(0, |n| n.saturating_sub(1));
+    let message = match stderr.find("panicked at") {
+        Some(start) => stderr[start..]
+            .lines()
+            .take_while(|line| !line.is_empty() && !line.starts_with("note:"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => match output.status.signal() {
+            Some(signal) => format!("Killed by signal {signal}"),
+            None => {
+                let lines = stderr.trim().lines().collect::<Vec<_>>();
+                lines[lines.len().saturating_sub(STDERR_LINES)..].join("\n")
+            }
+        },
+    };
+    Outcome::Panic { input, message }
+}
+
+/// How many lines of stderr a failure without a panic message is shown with
+const STDERR_LINES: usize = 20;
+
+/// How many processes the OOM killer has killed in the worker's cgroup so far, which
+/// submissions run in as well. Zero where that isn't known, such as outside of cgroup v2.
+/// Another request may run into the limit at the same time, which can't be told apart.
+fn oom_kills() -> u64 {
+    let Ok(cgroups) = std::fs::read_to_string("/proc/self/cgroup") else {
+        return 0;
+    };
+    let Some(cgroup) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) else {
+        return 0;
+    };
+    let events = Path::new("/sys/fs/cgroup")
+        .join(cgroup.trim_start_matches('/'))
+        .join("memory.events");
+    std::fs::read_to_string(events)
+        .ok()
+        .and_then(|events| {
+            events
+                .lines()
+                .find_map(|line| line.strip_prefix("oom_kill "))
+                .and_then(|n| n.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{env, fs, fs::Permissions, os::unix::fs::PermissionsExt, sync::Arc};
+
+    use worker::{Language, Limits};
+
+    use super::*;
+    use crate::{job::tests::test_users, workspace::Workspaces};
+
+    pub(crate) const LIMITS: Limits = Limits {
+        compile_timeout: Duration::from_secs(60),
+        run_timeout: Duration::from_secs(60),
+        max_instructions: None,
+    };
+
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ferris-elf-test-{name}-{}", std::process::id()))
+    }
+
+    /// A stand-in for the runner crate without any dependencies
+    pub(crate) fn runner_crate(dir: &Path, code: &str) {
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("benches")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "runner"
+version = "0.1.0"
+edition = "2024"
+
+[[bench]]
+name = "bench"
+harness = false
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("benches/bench.rs"),
+            "fn main() { println!(\"{}\", runner::run(\"\")); }",
+        )
+        .unwrap();
+        fs::write(dir.join("src/lib.rs"), code).unwrap();
+    }
+
+    /// Workspaces of the runner crates in `runners`, running submissions as users of their
+    /// own whenever the tests can switch users
+    pub(crate) fn isolated(
+        runners: HashMap<Runner, PathBuf>,
+        dir: &Path,
+        parallelism: usize,
+    ) -> Arc<Workspaces> {
+        let first_uid = test_users().map(|(builder, first_uid)| {
+            for runner_dir in runners.values() {
+                builder.chown_all(runner_dir).unwrap();
+            }
+            first_uid
+        });
+        Workspaces::new(runners, dir.join("jobs"), parallelism, first_uid)
+    }
+
+    /// The only workspace of a stand-in runner crate in `dir`
+    pub(crate) async fn workspace(dir: &Path) -> Workspace {
+        runner_crate(&dir.join("runner"), "");
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        Workspaces::new(runners, dir.join("jobs"), 1, None)
+            .checkout(Runner::Rust)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn inputs_are_not_visible_at_compile_time() {
+        let dir = test_dir("inputs");
+        runner_crate(&dir.join("runner"), "");
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = isolated(runners, &dir, 2);
+        let workspace = workspaces.checkout(Runner::Rust).await.unwrap();
+        // The inputs of another request, staged while this one is built
+        let other = workspaces.checkout(Runner::Rust).await.unwrap();
+        let other_dir = JobDir::create(1, other.submitter()).await.unwrap();
+        let other_inputs = other_dir.stage_inputs(&[b"42".to_vec()]).await.unwrap();
+
+        // Where its own inputs would be if the dirs of requests weren't named at random
+        let mut paths = vec![env::temp_dir().join("ferris-elf-0/inputs/INPUT_1")];
+        // Those of others can only be kept from builds by running them as other users
+        if workspace.submitter().is_some() {
+            paths.push(other_inputs.join("INPUT_1"));
+        }
+        for path in paths {
+            let req = Request {
+                id: 0,
+                part: Some(1),
+                inputs: vec![b"42".to_vec()],
+                code: format!("pub fn run(_: &str) -> &'static str {{ include_str!({path:?}) }}")
+                    .into(),
+                language: Language::Rust,
+                limits: LIMITS,
+                callgrind_args: Vec::new(),
+                wall_clock: None,
+            };
+            match benchmark(&workspace, &req, drop).await.unwrap() {
+                Outcome::CompileError(diagnostics) => {
+                    assert!(diagnostics.contains("couldn't read"), "got {diagnostics}")
+                }
+                outcome => panic!("expected a compile error, got {outcome:?}"),
+            }
+        }
+        drop((workspace, other, other_dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn builds_are_measured() {
+        let dir = test_dir("build");
+        let workspace = workspace(&dir).await;
+        fs::write(
+            workspace.dir().join("src/lib.rs"),
+            "pub fn run(input: &str) -> usize { input.len() }",
+        )
+        .unwrap();
+
+        let rust = template(Language::Rust);
+        let built = build(&workspace, &rust, Shape::Run, false, LIMITS.compile_timeout)
+            .await
+            .unwrap();
+        drop(workspace);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let Ok((_, build, _)) = built else {
+            panic!("expected the build to succeed");
+        };
+        assert!(build.compile_time > Duration::ZERO);
+        assert!(build.codegen_units >= 1);
+        assert!(build.text_size > 0);
+    }
+
+    #[tokio::test]
+    async fn c_submissions_are_linked_into_the_harness() {
+        let dir = test_dir("native");
+        // The native runner crate, with a harness that prints the answer instead
+        let native = dir.join("native");
+        runner_crate(&native, "");
+        let sources = Path::new(env!("CARGO_MANIFEST_DIR")).join("../native");
+        fs::copy(sources.join("build.rs"), native.join("build.rs")).unwrap();
+        fs::copy(sources.join("src/lib.rs"), native.join("src/lib.rs")).unwrap();
+        fs::write(
+            native.join("benches/bench.rs"),
+            "fn main() { println!(\"{}\", runner::run(b\"1\\n2\\n\")); }",
+        )
+        .unwrap();
+        let runners = HashMap::from([(Runner::Native, native)]);
+        let workspace = Workspaces::new(runners, dir.join("jobs"), 1, None)
+            .checkout(Runner::Native)
+            .await
+            .unwrap();
+        fs::write(
+            workspace.dir().join("submission.c"),
+            r#"
+#include <stddef.h>
+#include <stdio.h>
+
+size_t run(const unsigned char *input, size_t len, char *out, size_t cap) {
+    size_t lines = 0;
+    for (size_t i = 0; i < len; i++) lines += input[i] == '\n';
+    return snprintf(out, cap, "%zu lines", lines);
+}"#,
+        )
+        .unwrap();
+
+        let c = template(Language::C);
+        let built = build(&workspace, &c, Shape::Run, false, LIMITS.compile_timeout)
+            .await
+            .unwrap();
+        let (executables, build, _) = match built {
+            Ok(built) => built,
+            Err(outcome) => panic!("expected the build to succeed, got {outcome:?}"),
+        };
+        let output = Command::new(&executables.bench).output().await.unwrap();
+        drop(workspace);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "2 lines\n");
+        assert_eq!(build.codegen_units, 1);
+    }
+
+    #[tokio::test]
+    async fn every_input_is_timed_separately() {
+        let dir = test_dir("wall");
+        fs::create_dir_all(&dir).unwrap();
+        let job_dir = JobDir::create(-3, None).await.unwrap();
+        // Stands in for the wall-clock harness, timing whichever input it is given
+        let wall = dir.join("wall");
+        fs::write(
+            &wall,
+            "#!/bin/sh\nprintf 'run\\t%s\\t%s\\t3\\t%s\\n' \"$1\" \"${1#INPUT_}00\" \"$SAMPLES\" >> \"$TIMINGS_PATH\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&wall, Permissions::from_mode(0o755)).unwrap();
+
+        let wall_clock = WallClock {
+            warmup: Duration::ZERO,
+            samples: 10,
+        };
+        let timings = time(
+            &dir,
+            &wall,
+            wall_clock,
+            &job_dir,
+            2,
+            LIMITS.run_timeout,
+            drop,
+        )
+        .await
+        .unwrap();
+        drop(job_dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let timings = timings.unwrap().remove("run").unwrap();
+        let medians = timings
+            .iter()
+            .map(|t| t.median.as_nanos())
+            .collect::<Vec<_>>();
+        assert_eq!(medians, [100, 200]);
+        assert!(timings.iter().all(|t| t.samples == 10));
+    }
+
+    #[tokio::test]
+    async fn endless_runs_time_out() {
+        let dir = test_dir("timeout");
+        let workspace = workspace(&dir).await;
+
+        let req = Request {
+            id: 1,
+            part: Some(1),
+            inputs: vec![b"42".to_vec()],
+            code: b"pub fn run(_: &str) -> u8 { loop { std::hint::black_box(()); } }".to_vec(),
+            language: Language::Rust,
+            limits: Limits {
+                run_timeout: Duration::from_secs(1),
+                ..LIMITS
+            },
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        };
+        let outcome = benchmark(&workspace, &req, drop).await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(outcome, Outcome::RunTimeout), "got {outcome:?}");
+    }
+
+    /// The runner crate with its real harness, but none of the dependencies it offers
+    /// submissions
+    fn harness_crate(dir: &Path) {
+        runner_crate(dir, "");
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "runner"
+version = "0.1.0"
+edition = "2024"
+
+[[bench]]
+name = "bench"
+harness = false
+
+[features]
+split = []
+parts = []
+
+[profile.bench]
+debug = true
+
+[dev-dependencies]
+iai-callgrind = "0.14.0"
+"#,
+        )
+        .unwrap();
+        let harness = Path::new(env!("CARGO_MANIFEST_DIR")).join("../runner/benches");
+        fs::copy(harness.join("bench.rs"), dir.join("benches/bench.rs")).unwrap();
+        fs::create_dir_all(dir.join("benches/common")).unwrap();
+        fs::copy(
+            harness.join("common/mod.rs"),
+            dir.join("benches/common/mod.rs"),
+        )
+        .unwrap();
+    }
+
+    /// Whether valgrind and iai-callgrind-runner are installed, which CI says by setting
+    /// `VALGRIND_TESTS` when running the tests in the image
+    fn valgrind() -> bool {
+        let installed = env::var_os("VALGRIND_TESTS").is_some();
+        if !installed {
+            eprintln!("Not counting instructions without VALGRIND_TESTS");
+        }
+        installed
+    }
+
+    fn instructions(outcome: Outcome) -> Vec<u64> {
+        match outcome {
+            Outcome::Success { parts, .. } => {
+                parts[0].inputs.iter().map(|i| i.instructions).collect()
+            }
+            outcome => panic!("expected the run to succeed, got {outcome:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_runs_count_the_same_instructions() {
+        if !valgrind() {
+            return;
+        }
+        let dir = test_dir("counts");
+        harness_crate(&dir.join("runner"));
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = isolated(runners, &dir, 2);
+
+        let measured = |id| Request {
+            id,
+            part: Some(1),
+            inputs: vec![b"1 2 3".to_vec(), b"45 6 789".to_vec(), b"0".to_vec()],
+            code: br#"
+pub fn run(input: &str) -> u64 {
+    input.split(' ').map(|n| n.parse::<u64>().unwrap()).sum()
+}"#
+            .to_vec(),
+            language: Language::Rust,
+            limits: LIMITS,
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        };
+        // Keeps valgrind busy for as long as the measured submission runs
+        let busy = Request {
+            code: br#"
+pub fn run(input: &str) -> u64 {
+    (0..20_000_000u64).fold(input.len() as u64, |acc, i| acc.wrapping_mul(31) ^ i)
+}"#
+            .to_vec(),
+            ..measured(12)
+        };
+
+        let alone = benchmark(
+            &workspaces.checkout(Runner::Rust).await.unwrap(),
+            &measured(10),
+            drop,
+        )
+        .await
+        .unwrap();
+        let (first, second) = (
+            workspaces.checkout(Runner::Rust).await.unwrap(),
+            workspaces.checkout(Runner::Rust).await.unwrap(),
+        );
+        let req = measured(11);
+        let (_, together) = tokio::join!(
+            benchmark(&first, &busy, drop),
+            benchmark(&second, &req, drop)
+        );
+        drop((first, second));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(instructions(alone), instructions(together.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn summaries_changed_by_the_submission_are_rejected() {
+        if !valgrind() {
+            return;
+        }
+        let dir = test_dir("forged");
+        harness_crate(&dir.join("runner"));
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = isolated(runners, &dir, 1);
+
+        // Leaves a process behind that claims the first input took a single instruction
+        // once its summary is saved, while the second input is still running
+        let req = Request {
+            id: 13,
+            part: Some(1),
+            inputs: vec![b"1".to_vec(), b"2".to_vec()],
+            code: br##"
+use std::{os::unix::process::CommandExt, process::{Command, Stdio}, thread, time::Duration};
+
+const FORGE: &str = r#"
+    until f=$(find "$INPUTS_DIR/.." -name summary.json) && [ -n "$f" ]; do sleep 0.1; done
+    sleep 2
+    sed -i -E 's/"Left":[0-9]+/"Left":1/g' $f
+"#;
+
+pub fn run(input: &str) -> u64 {
+    if input == "1" {
+        let _ = Command::new("sh")
+            .args(["-c", FORGE])
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+    } else {
+        thread::sleep(Duration::from_secs(5));
+    }
+    1
+}"##
+            .to_vec(),
+            language: Language::Rust,
+            limits: LIMITS,
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        };
+        let workspace = workspaces.checkout(Runner::Rust).await.unwrap();
+        let outcome = benchmark(&workspace, &req, drop).await.unwrap();
+        drop(workspace);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match outcome {
+            Outcome::InvalidResults(e) => assert!(e.contains("changed after it was saved"), "{e}"),
+            outcome => panic!("expected the run to be rejected, got {outcome:?}"),
+        }
+    }
+
+    fn failed(status: i32, stderr: &str) -> Output {
+        Output {
+            status: std::process::ExitStatus::from_raw(status),
+            stdout: Vec::new(),
+            stderr: stderr.into(),
+        }
+    }
+
+    #[test]
+    fn only_the_oom_killer_means_out_of_memory() {
+        // What the submission writes can't make it look killed for memory
+        let forged = failed(
+            1 << 8,
+            "Terminated by a signal '9'\nmemory allocation of 8 bytes failed",
+        );
+        assert!(matches!(
+            classify_failure(&forged, false),
+            Outcome::Panic { .. }
+        ));
+        assert!(matches!(
+            classify_failure(&forged, true),
+            Outcome::MemoryLimit
+        ));
+    }
+
+    #[test]
+    fn failures_are_told_by_what_the_submission_printed() {
+        let panicked = failed(
+            101 << 8,
+            "Panicked while running INPUT_2\nthread 'main' panicked at src/lib.rs:1:1:\noops\n\nnote: backtrace",
+        );
+        match classify_failure(&panicked, false) {
+            Outcome::Panic { input, message } => {
+                assert_eq!(input, 1);
+                assert_eq!(message, "panicked at src/lib.rs:1:1:\noops");
+            }
+            outcome => panic!("unexpected {outcome:?}"),
+        }
+
+        // Such as killing itself
+        let killed = failed(libc::SIGKILL, "");
+        match classify_failure(&killed, false) {
+            Outcome::Panic { message, .. } => assert_eq!(message, "Killed by signal 9"),
+            outcome => panic!("unexpected {outcome:?}"),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Framing for messages between the bot and workers.
+//!
+//! Every message is sent as a frame of `MAGIC`, the protocol version and the length of the
+//! bincode encoded message, all little endian. The version is checked on every frame so a
+//! bot and a worker built from different commits refuse each other instead of misreading
+//! messages. A worker starts by sending a [`Hello`](crate::Hello) and the bot by sending a
+//! [`Greeting`](crate::Greeting). The worker then sends a [`Message`](crate::Message) for
+//! each bit of progress and each response to a [`Request`](crate::Request) it receives.
+//! Requests and messages are wrapped in [`Signed`](crate::Signed), see
+//! [`auth`](crate::auth).
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const MAGIC: [u8; 4] = *b"FELF";
+/// Bumped whenever the encoding of any message changes
+pub const PROTOCOL_VERSION: u32 = 10;
+/// Frames claiming to be longer than this are rejected rather than allocated for
+pub const MAX_FRAME_LEN: u32 = 256 << 20;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The stream does not contain frames at all
+    BadMagic([u8; 4]),
+    /// The other side speaks a different version of the protocol
+    Version(u32),
+    TooLarge(u32),
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{e}"),
+            FrameError::BadMagic(magic) => write!(f, "Not a frame, started with {magic:?}"),
+            FrameError::Version(version) => write!(
+                f,
+                "Protocol version {version} is not supported, expected {PROTOCOL_VERSION}"
+            ),
+            FrameError::TooLarge(len) => {
+                write!(
+                    f,
+                    "Frame of {len} bytes is over the limit of {MAX_FRAME_LEN}"
+                )
+            }
+            FrameError::Bincode(e) => write!(f, "Failed to encode or decode message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Writes a message as a single frame and flushes it
+pub fn write_frame<T: Serialize>(w: &mut impl Write, msg: &T) -> Result<(), FrameError> {
+    let payload = bincode::serialize(msg).map_err(FrameError::Bincode)?;
+    let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+
+    w.write_all(&MAGIC)?;
+    w.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&payload)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Reads the next frame, or `None` if the stream ended cleanly between frames
+pub fn read_frame<T: DeserializeOwned>(r: &mut impl Read) -> Result<Option<T>, FrameError> {
+    let mut magic = [0; 4];
+    let mut filled = 0;
+    while filled < magic.len() {
+        match r.read(&mut magic[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if magic != MAGIC {
+        return Err(FrameError::BadMagic(magic));
+    }
+
+    let mut word = [0; 4];
+    r.read_exact(&mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version != PROTOCOL_VERSION {
+        return Err(FrameError::Version(version));
+    }
+    r.read_exact(&mut word)?;
+    let len = u32::from_le_bytes(word);
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    let msg = bincode::deserialize(&payload).map_err(FrameError::Bincode)?;
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::*;
+    use crate::{
+        Capability, Hello, Language, Limits, Message, Outcome, Progress, Request, Response,
+        WallClock,
+    };
+
+    fn round_trip<T: Serialize + DeserializeOwned>(msg: &T) -> T {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, msg).unwrap();
+        let mut r = Cursor::new(buf);
+        let msg = read_frame(&mut r).unwrap().unwrap();
+        assert!(read_frame::<T>(&mut r).unwrap().is_none());
+        msg
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let req = Request {
+            id: 7,
+            part: Some(2),
+            inputs: vec![b"1\n2\n".to_vec(), vec![0, 255]],
+            code: b"size_t run(const char *in, size_t len, char *out, size_t cap) { return 0; }"
+                .to_vec(),
+            language: Language::C,
+            limits: Limits {
+                compile_timeout: Duration::from_secs(1),
+                run_timeout: Duration::from_millis(1500),
+                max_instructions: Some(42),
+            },
+            callgrind_args: vec!["--cache-sim=yes".into()],
+            wall_clock: Some(WallClock {
+                warmup: Duration::from_millis(200),
+                samples: 50,
+            }),
+        };
+        let res = round_trip(&req);
+        assert_eq!(res.id, req.id);
+        assert_eq!(res.part, req.part);
+        assert_eq!(res.inputs, req.inputs);
+        assert_eq!(res.code, req.code);
+        assert_eq!(res.language, Language::C);
+        assert_eq!(res.limits.run_timeout, req.limits.run_timeout);
+        assert_eq!(res.limits.max_instructions, Some(42));
+        assert_eq!(res.callgrind_args, req.callgrind_args);
+        assert_eq!(res.wall_clock.map(|w| w.samples), Some(50));
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let res = round_trip(&Response {
+            id: 3,
+            outcome: Outcome::Panic {
+                input: 1,
+                message: "oh no".into(),
+            },
+        });
+        assert_eq!(res.id, 3);
+        match res.outcome {
+            Outcome::Panic { input, message } => {
+                assert_eq!(input, 1);
+                assert_eq!(message, "oh no");
+            }
+            outcome => panic!("expected a panic, got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn progress_round_trips() {
+        let msg = round_trip(&Message::Progress {
+            id: 4,
+            progress: Progress::Benchmarking {
+                current: 2,
+                total: 6,
+            },
+        });
+        match msg {
+            Message::Progress { id, progress } => {
+                assert_eq!(id, 4);
+                assert_eq!(
+                    progress,
+                    Progress::Benchmarking {
+                        current: 2,
+                        total: 6
+                    }
+                );
+            }
+            msg => panic!("expected progress, got {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = Hello::new(
+            "rustc 1.85.0".into(),
+            Some("sha256:abc".into()),
+            &[Language::Zig],
+            true,
+        );
+        let res = round_trip(&hello);
+        assert_eq!(res.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(res.capabilities[..Capability::ALL.len()], Capability::ALL);
+        assert_eq!(
+            res.capabilities[Capability::ALL.len()..],
+            [Capability::Language(Language::Zig), Capability::Isolation]
+        );
+        assert_eq!(res.toolchain, "rustc 1.85.0");
+        assert_eq!(res.image_digest.as_deref(), Some("sha256:abc"));
+    }
+
+    #[test]
+    fn consecutive_frames_are_read_in_order() {
+        let mut buf = Vec::new();
+        for id in 0..3i64 {
+            write_frame(&mut buf, &id).unwrap();
+        }
+        let mut r = Cursor::new(buf);
+        for id in 0..3i64 {
+            assert_eq!(read_frame::<i64>(&mut r).unwrap(), Some(id));
+        }
+        assert_eq!(read_frame::<i64>(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &0i64).unwrap();
+        buf[4..8].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        let err = read_frame::<i64>(&mut Cursor::new(buf)).unwrap_err();
+        assert!(matches!(err, FrameError::Version(v) if v == PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn unframed_data_is_rejected() {
+        // What a worker from before framing would send
+        let buf = bincode::serialize(&0i64).unwrap();
+        let err = read_frame::<i64>(&mut Cursor::new(buf)).unwrap_err();
+        assert!(matches!(err, FrameError::BadMagic(_)));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(MAX_FRAME_LEN + 1).to_le_bytes());
+        let err = read_frame::<i64>(&mut Cursor::new(buf)).unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge(_)));
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &0i64).unwrap();
+        buf.truncate(buf.len() - 1);
+        let err = read_frame::<i64>(&mut Cursor::new(buf)).unwrap_err();
+        assert!(matches!(err, FrameError::Io(_)));
+    }
+}
@@ -0,0 +1,318 @@
+//! What a request has to itself while it runs: a dir to stage its inputs in and collect its
+//! results from, and the user its submission runs as.
+//!
+//! A worker with `SUBMISSION_UID` set runs submissions as a user of their own for every
+//! slot, one that owns nothing but what it writes in the dir of the request in that slot.
+//! The runner crates, their build output and the tools on `PATH` stay read-only to them,
+//! and whatever a submission leaves running is killed before the slot is used again.
+//! Builds run as whoever owns the runner crate, which can't read the dirs of requests
+//! either. Otherwise everything runs as the worker itself, which is only fit for trusted
+//! code.
+//!
+//! Switching users is the only privilege this takes, `CAP_SETUID` and `CAP_SETGID`. The
+//! worker reaches the files of builds and submissions by being in their groups, and kills
+//! their processes by switching to them first. Nothing started as another user keeps any
+//! capability, nor can gain one.
+//!
+//! The dirs of requests are named at random as well, so that the submission of one
+//! request can't find those of others, nor its own at compile time.
+
+use std::{
+    env, fs,
+    fs::Permissions,
+    io,
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt, chown},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    ptr,
+};
+
+use tokio::{
+    fs::{create_dir, set_permissions, write},
+    process::Command,
+};
+
+use crate::Error;
+
+/// `CAP_SETGID` and `CAP_SETUID` in the capability sets of `/proc/self/status`
+const SWITCHING_USERS: u64 = 1 << 6 | 1 << 7;
+
+/// Someone to run commands as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl User {
+    /// Whoever owns the file at `path`
+    pub fn owner(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+
+    /// Changes the owner of `path` and everything in it to this user
+    #[cfg(test)]
+    pub fn chown_all(self, path: &Path) -> io::Result<()> {
+        chown(path, Some(self.uid), Some(self.gid))?;
+        if fs::symlink_metadata(path)?.is_dir() {
+            for entry in fs::read_dir(path)? {
+                self.chown_all(&entry?.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Kills every process running as this user, which only the submissions of one slot
+    /// ever do. Escaping the process group of a run doesn't escape this.
+    fn kill_all(self) -> io::Result<()> {
+        self.kill(-1)
+    }
+
+    /// Kills every process in the group `pgid`, which are this user's
+    pub fn kill_group(self, pgid: u32) -> io::Result<()> {
+        self.kill(-(pgid as libc::pid_t))
+    }
+
+    /// Signals `pid` like `kill` does, as this user. That reaches nothing but this user's
+    /// processes, without the worker needing to be allowed to kill those of others.
+    fn kill(self, pid: libc::pid_t) -> io::Result<()> {
+        let mut cmd = std::process::Command::new("true");
+        switch_to(&mut cmd, self);
+        // SAFETY: kill is async-signal-safe. It runs after switching users, so it never
+        // reaches the caller itself.
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::kill(pid, libc::SIGKILL);
+                Ok(())
+            })
+        };
+        cmd.status()?;
+        Ok(())
+    }
+}
+
+/// Whether the worker may switch to other users
+pub fn can_switch_users() -> bool {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & SWITCHING_USERS == SWITCHING_USERS)
+}
+
+/// Adds `gids` to the groups of the worker, which is how it reaches what builds and
+/// submissions write
+pub fn join_groups(gids: &[u32]) -> io::Result<()> {
+    // SAFETY: gids outlives the call and its length is passed along with it
+    if unsafe { libc::setgroups(gids.len(), gids.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `prefix` followed by enough randomness that nobody can guess it
+pub fn random_name(prefix: &str) -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("no randomness available");
+    bytes
+        .iter()
+        .fold(format!("{prefix}-"), |name, b| format!("{name}{b:02x}"))
+}
+
+/// Makes `cmd` run as `user`, or as the worker itself without one
+pub fn run_as(cmd: &mut Command, user: Option<User>) -> &mut Command {
+    if let Some(user) = user {
+        switch_to(cmd.as_std_mut(), user);
+    }
+    cmd
+}
+
+/// Switches to `user` before `cmd` runs, leaving the groups and capabilities of the worker
+/// behind. What it writes stays writable to its group, which the worker is in.
+fn switch_to(cmd: &mut std::process::Command, user: User) {
+    // SAFETY: all of these are async-signal-safe system calls
+    unsafe {
+        cmd.pre_exec(move || {
+            let switched = libc::setgroups(0, ptr::null()) == 0
+                && libc::setgid(user.gid) == 0
+                && libc::setuid(user.uid) == 0
+                && libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                    0,
+                    0,
+                    0,
+                ) == 0
+                && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0;
+            if !switched {
+                return Err(io::Error::last_os_error());
+            }
+            libc::umask(0o002);
+            Ok(())
+        })
+    };
+}
+
+/// Removed along with everything in it when dropped, however the request ends. Anything
+/// the submission left running is killed first.
+pub struct JobDir {
+    path: PathBuf,
+    /// Only ever used by the worker itself
+    private: PathBuf,
+    user: Option<User>,
+}
+
+impl JobDir {
+    /// Creates a fresh dir for request `id` that only `user` and the worker may use, so
+    /// that nothing from a previous run can be mistaken for a result. It belongs to the
+    /// worker, and `user` may only remove what it wrote itself.
+    pub async fn create(id: i64, user: Option<User>) -> Result<Self, Error> {
+        let path = env::temp_dir().join(random_name(&format!("ferris-elf-{id}")));
+        create_dir(&path).await?;
+        let mode = match user {
+            Some(user) => {
+                chown(&path, None, Some(user.gid))?;
+                0o3770
+            }
+            None => 0o700,
+        };
+        set_permissions(&path, Permissions::from_mode(mode)).await?;
+        let private = env::temp_dir().join(random_name(&format!("ferris-elf-{id}-private")));
+        create_dir(&private).await?;
+        set_permissions(&private, Permissions::from_mode(0o700)).await?;
+        Ok(Self {
+            path,
+            private,
+            user,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A dir next to the one of the request that the submission can't write to, nor find
+    pub fn private(&self) -> &Path {
+        &self.private
+    }
+
+    /// Who the submission runs as
+    pub fn user(&self) -> Option<User> {
+        self.user
+    }
+
+    /// Writes `inputs` to files named after the variables the harness used to read them
+    /// from, returning the dir they are in. Files rather than environment variables, which
+    /// are limited in size and can't hold NUL.
+    pub async fn stage_inputs(&self, inputs: &[Vec<u8>]) -> Result<PathBuf, Error> {
+        let dir = self.path.join("inputs");
+        create_dir(&dir).await?;
+        for (i, input) in inputs.iter().enumerate() {
+            let path = dir.join(format!("INPUT_{}", i + 1));
+            write(&path, input).await?;
+            set_permissions(&path, Permissions::from_mode(0o444)).await?;
+        }
+        Ok(dir)
+    }
+
+    /// Creates an empty file the submission may write to, returning its path
+    pub async fn writable_file(&self, name: &str) -> Result<PathBuf, Error> {
+        let path = self.path.join(name);
+        write(&path, "").await?;
+        set_permissions(&path, Permissions::from_mode(0o660)).await?;
+        Ok(path)
+    }
+
+    /// Kills whatever the submission left running, if it runs as a user of its own
+    pub fn kill_all(&self) {
+        if let Some(user) = self.user
+            && let Err(e) = user.kill_all()
+        {
+            eprintln!("Failed to kill the processes of uid {}: {e}", user.uid);
+        }
+    }
+}
+
+impl Drop for JobDir {
+    fn drop(&mut self) {
+        self.kill_all();
+        // The submission may have written dirs the worker can't look into
+        if let Some(user) = self.user {
+            let mut rm = std::process::Command::new("rm");
+            switch_to(rm.args(["-rf", "--"]).arg(&self.path), user);
+            let _ = rm.stderr(Stdio::null()).status();
+        }
+        for path in [&self.path, &self.private] {
+            if let Err(e) = fs::remove_dir_all(path) {
+                eprintln!("Failed to remove {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    /// Users for tests that switch users, which needs them to run as root
+    pub(crate) fn test_users() -> Option<(User, u32)> {
+        // SAFETY: geteuid has no memory safety requirements
+        let root = unsafe { libc::geteuid() } == 0;
+        if !root {
+            eprintln!("Not switching users without running as root");
+        }
+        root.then_some((
+            User {
+                uid: 61000,
+                gid: 61000,
+            },
+            62000,
+        ))
+    }
+
+    #[tokio::test]
+    async fn dirs_are_removed_when_dropped() {
+        let dir = JobDir::create(-1, None).await.unwrap();
+        let path = dir.path().to_owned();
+        let private = dir.private().to_owned();
+        fs::create_dir(path.join("inputs")).unwrap();
+        fs::write(path.join("inputs/INPUT_1"), "42").unwrap();
+        fs::write(private.join("summary.json"), "{}").unwrap();
+
+        drop(dir);
+        assert!(!path.exists());
+        assert!(!private.exists());
+    }
+
+    #[tokio::test]
+    async fn processes_left_behind_are_killed() {
+        let Some((_, uid)) = test_users() else {
+            return;
+        };
+        let user = User { uid, gid: uid };
+        let dir = JobDir::create(-2, Some(user)).await.unwrap();
+        assert_eq!(fs::metadata(dir.path()).unwrap().gid(), user.gid);
+
+        // In a process group of its own, like a child a submission spawned to escape
+        let mut child = run_as(&mut Command::new("sleep"), Some(user))
+            .arg("60")
+            .process_group(0)
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        drop(dir);
+
+        let status = child.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+}
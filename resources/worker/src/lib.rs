@@ -1,15 +1,285 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf, str::FromStr, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+pub mod auth;
+pub mod codec;
+pub mod shape;
+
+pub use shape::Shape;
+
+/// Sent by a worker once when it starts, before any response
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    /// The output of `rustc --version` for the toolchain submissions are built with
+    pub toolchain: String,
+    /// Identifies the image the worker runs in, if it was built with one
+    pub image_digest: Option<String>,
+    /// Fresh for every connection, for authenticating the messages on it
+    pub challenge: auth::Challenge,
+}
+
+impl Hello {
+    /// For a worker building `languages` besides Rust
+    pub fn new(
+        toolchain: String,
+        image_digest: Option<String>,
+        languages: &[Language],
+        isolated: bool,
+    ) -> Self {
+        let languages = languages.iter().copied().map(Capability::Language);
+        let isolation = isolated.then_some(Capability::Isolation);
+        Self {
+            protocol_version: codec::PROTOCOL_VERSION,
+            capabilities: Capability::ALL
+                .into_iter()
+                .chain(languages)
+                .chain(isolation)
+                .collect(),
+            toolchain,
+            image_digest,
+            challenge: auth::challenge(),
+        }
+    }
+}
+
+/// Sent by the bot once, before any request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Greeting {
+    /// Fresh for every connection, like the worker's
+    pub challenge: auth::Challenge,
+}
+
+/// Features a worker may support beyond plain `run` submissions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Benchmarking `parse` and `solve` separately
+    Split,
+    /// Benchmarking `part1` and `part2` together
+    Parts,
+    /// Enforcing `Limits::max_instructions`
+    InstructionBudget,
+    /// Timing submissions as asked by `Request::wall_clock`
+    WallClock,
+    /// Building submissions in a language other than Rust
+    Language(Language),
+    /// Running every submission as a user of its own
+    Isolation,
+}
+
+impl Capability {
+    /// Everything this version of the worker supports, besides languages
+    pub const ALL: [Capability; 4] = [
+        Capability::Split,
+        Capability::Parts,
+        Capability::InstructionBudget,
+        Capability::WallClock,
+    ];
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     pub id: i64,
+    /// Ignored by submissions solving both parts
+    pub part: Option<u8>,
     pub inputs: Vec<Vec<u8>>,
     pub code: Vec<u8>,
+    pub language: Language,
+    pub limits: Limits,
+    /// Passed to callgrind after the harness's own args, such as `--cache-sim=yes`
+    pub callgrind_args: Vec<String>,
+    /// Also time the submission natively
+    pub wall_clock: Option<WallClock>,
+}
+
+impl Request {
+    /// What a worker needs to support to handle this request
+    pub fn required_capabilities(&self) -> Vec<Capability> {
+        let mut required = Vec::new();
+        match Shape::detect(self.language, &self.code) {
+            Shape::Run => {}
+            Shape::Split => required.push(Capability::Split),
+            Shape::Parts => required.push(Capability::Parts),
+        }
+        if self.language != Language::Rust {
+            required.push(Capability::Language(self.language));
+        }
+        if self.limits.max_instructions.is_some() {
+            required.push(Capability::InstructionBudget);
+        }
+        if self.wall_clock.is_some() {
+            required.push(Capability::WallClock);
+        }
+        required
+    }
+}
+
+/// How much a submission may spend on each stage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Limits {
+    pub compile_timeout: Duration,
+    /// For running every input, valgrind included
+    pub run_timeout: Duration,
+    /// The most instructions a single benchmark may take
+    pub max_instructions: Option<u64>,
+}
+
+/// How long to warm up before timing, then how many calls to time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WallClock {
+    pub warmup: Duration,
+    pub samples: u32,
+}
+
+/// A message with a MAC over it, when the connection is authenticated
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Signed<T> {
+    pub msg: T,
+    pub mac: Option<auth::Mac>,
+}
+
+/// Sent by a worker after its `Hello`, each one signed
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    /// How far along a request is, sent any number of times before its response
+    Progress { id: i64, progress: Progress },
+    /// The final word on a request
+    Response(Response),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// The worker has received the request
+    Accepted,
+    /// The limits apply from here on
+    Compiling,
+    /// With the warnings the compiler rendered, if any
+    Compiled { warnings: String },
+    /// Running benchmark `current` of `total`, counting from one
+    Benchmarking { current: usize, total: usize },
+    /// Timing input `current` of `total`, counting from one
+    Timing { current: usize, total: usize },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub id: i64,
-    pub outputs: Vec<Result<i64, String>>,
-    pub times: Vec<u64>,
+    pub outcome: Outcome,
+}
+
+/// How a submission fared against its inputs
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Outcome {
+    /// With results for each part the submission solves
+    Success {
+        parts: Vec<PartResult>,
+        build: Build,
+    },
+    /// With the compiler's diagnostics
+    CompileError(String),
+    /// While running the input at index `input`
+    Panic {
+        input: usize,
+        message: String,
+    },
+    CompileTimeout,
+    RunTimeout,
+    /// On the input at index `input`
+    InstructionLimit {
+        input: usize,
+        instructions: u64,
+    },
+    /// The bot gave up on the worker
+    Timeout,
+    MemoryLimit,
+    /// The harness reported results that don't match the inputs
+    InvalidResults(String),
+    /// Not the fault of the submission
+    InfrastructureError(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Language {
+    #[default]
+    Rust,
+    C,
+    Cpp,
+    Zig,
+}
+
+/// What building a submission took and produced
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Build {
+    /// Of the submission and the harness, but not the dependencies
+    pub compile_time: Duration,
+    /// Object files for languages other than Rust
+    pub codegen_units: u32,
+    /// Of the benchmark binary, in bytes
+    pub text_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartResult {
+    pub part: u8,
+    /// Results in input order
+    pub inputs: Vec<InputResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InputResult {
+    pub answer: String,
+    /// Including both phases of split submissions
+    pub instructions: u64,
+    pub parse_instructions: Option<u64>,
+    pub solve_instructions: Option<u64>,
+    pub metrics: Metrics,
+    /// The most bytes the heap held at once, input included
+    pub peak_heap: u64,
+    /// When asked to time the submission
+    pub timing: Option<Timing>,
+}
+
+/// Wall-clock time without outliers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub median: Duration,
+    /// The interquartile range
+    pub spread: Duration,
+    /// How many calls were kept
+    pub samples: u32,
+}
+
+/// Callgrind events by their iai-callgrind names, such as `Ir`
+pub type Metrics = BTreeMap<String, u64>;
+
+/// Where a worker listens for the bot, written as `tcp:HOST:PORT` or `unix:PATH`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("tcp", addr)) if !addr.is_empty() => Ok(Endpoint::Tcp(addr.into())),
+            Some(("unix", path)) if !path.is_empty() => Ok(Endpoint::Unix(path.into())),
+            _ => Err(format!(
+                "Invalid endpoint {s:?}, expected tcp:HOST:PORT or unix:PATH"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
@@ -1,123 +1,422 @@
-use core::str;
 use std::{
-    ffi::OsStr,
-    io::{BufReader, BufWriter, Write},
-    os::unix::ffi::OsStrExt,
+    collections::HashMap,
+    env, fs,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
 };
 
-use tokio::{fs::write, process::Command, select, sync::mpsc, task::JoinSet};
-use worker::{Request, Response};
+use job::User;
+use template::{Runner, template};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    process::Command,
+    select,
+    sync::{mpsc, oneshot},
+    task::{self, JoinSet},
+};
+use tokio_util::io::SyncIoBridge;
+use worker::{
+    Endpoint, Greeting, Hello, Language, Message, Outcome, Progress, Request, Response, Signed,
+    auth::{Key, Session},
+    codec::{read_frame, write_frame},
+};
+use workspace::Workspaces;
+
+mod artifacts;
+mod bench;
+mod job;
+mod summary;
+mod template;
+mod workspace;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
-const RUNNER_DIR: &'static str = "/runner";
+/// Where the runner crate is unless `RUNNER_DIR` says otherwise
+const DEFAULT_RUNNER_DIR: &str = "/runner";
+/// Where the runner crate for languages other than Rust is unless `NATIVE_RUNNER_DIR` says
+/// otherwise. Those languages are only supported if it exists.
+const DEFAULT_NATIVE_RUNNER_DIR: &str = "/native";
+/// How many requests are handled at once unless `MAX_PARALLEL_JOBS` says otherwise
+const DEFAULT_MAX_PARALLEL_JOBS: usize = 1;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let (in_tx, in_rx) = mpsc::channel(4);
-    let (out_tx, mut out_rx) = mpsc::channel(4);
+    let runner_dir = env::var_os("RUNNER_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_RUNNER_DIR.into());
+
+    let toolchain = Command::new("rustc")
+        .arg("--version")
+        .current_dir(&runner_dir)
+        .output()
+        .await?;
+    let toolchain = String::from_utf8_lossy(&toolchain.stdout).trim().to_owned();
+    let image_digest = env::var("IMAGE_DIGEST")
+        .ok()
+        .filter(|digest| !digest.is_empty());
+
+    let mut runner_dirs = HashMap::from([(Runner::Rust, runner_dir)]);
+    let native_dir = env::var_os("NATIVE_RUNNER_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_NATIVE_RUNNER_DIR.into());
+    let mut languages = Vec::new();
+    if native_dir.exists() {
+        for language in [Language::C, Language::Cpp, Language::Zig] {
+            let version = template(language).version;
+            let installed = Command::new(version[0])
+                .args(&version[1..])
+                .output()
+                .await
+                .is_ok_and(|output| output.status.success());
+            if installed {
+                languages.push(language);
+            }
+        }
+        runner_dirs.insert(Runner::Native, native_dir);
+    }
+
+    // Submissions run as users of their own from this uid on, one for every parallel job
+    let first_uid = env::var("SUBMISSION_UID")
+        .ok()
+        .map(|uid| uid.parse::<u32>().map_err(|_| "invalid SUBMISSION_UID"))
+        .transpose()?;
+    if first_uid.is_some() && !job::can_switch_users() {
+        return Err("SUBMISSION_UID needs the worker to have CAP_SETUID and CAP_SETGID".into());
+    }
+    let isolated = first_uid.is_some();
+    let hello = || {
+        Hello::new(
+            toolchain.clone(),
+            image_digest.clone(),
+            &languages,
+            isolated,
+        )
+    };
 
-    let handler = tokio::spawn(handle_messages(in_rx, out_tx));
+    // Shared by every connection, so the limit holds however many bots connect
+    let parallelism = env::var("MAX_PARALLEL_JOBS")
+        .map(|n| n.parse().map_err(|_| "invalid MAX_PARALLEL_JOBS"))
+        .unwrap_or(Ok(DEFAULT_MAX_PARALLEL_JOBS))?;
+    let jobs_dir = env::temp_dir().join(format!("ferris-elf-jobs-{}", std::process::id()));
+    // The groups of the builders and of every slot's user are all it takes to reach their
+    // files
+    if let Some(first_uid) = first_uid {
+        let mut gids = runner_dirs
+            .values()
+            .map(|dir| Ok(User::owner(dir)?.gid))
+            .collect::<Result<Vec<_>, Error>>()?;
+        gids.extend((0..parallelism as u32).map(|slot| first_uid + slot));
+        job::join_groups(&gids)?;
+    }
+    let workspaces = Workspaces::new(runner_dirs, jobs_dir, parallelism, first_uid);
 
-    let mut io = JoinSet::<Result<(), Error>>::new();
+    // Required of the bot whenever it is set, and for listening on TCP
+    let key = env::var("WORKER_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| Key::new(secret.as_bytes()));
 
-    io.spawn_blocking(move || {
-        let stdin = std::io::stdin();
-        let mut stdin = BufReader::new(stdin);
+    // Without an endpoint to listen on, the bot started this worker and talks over stdio
+    let Ok(listen) = env::var("WORKER_LISTEN") else {
+        let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+        return serve(workspaces, hello(), key, stdin, stdout).await;
+    };
 
-        loop {
-            let req = bincode::deserialize_from(&mut stdin);
-            if req.as_ref().is_err_and(|e| {
-                if let bincode::ErrorKind::Io(e) = &**e {
-                    return e.kind() == std::io::ErrorKind::UnexpectedEof;
+    // A bot runs several requests at a time over one connection, and more bots may connect
+    match listen.parse::<Endpoint>()? {
+        Endpoint::Tcp(addr) => {
+            if key.is_none() {
+                return Err("WORKER_SECRET must be set to listen on TCP".into());
+            }
+            let listener = TcpListener::bind(&addr).await?;
+            eprintln!("Listening on {listen}");
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                let (input, output) = stream.into_split();
+                let (input, output) = (SyncIoBridge::new(input), SyncIoBridge::new(output));
+                let connection = serve(workspaces.clone(), hello(), key.clone(), input, output);
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("Connection from {peer} failed: {e}");
+                    }
+                });
+            }
+        }
+        Endpoint::Unix(path) => {
+            // Left behind by an earlier worker that didn't get to clean up
+            let _ = fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)?;
+            eprintln!("Listening on {listen}");
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let (input, output) = stream.into_split();
+                let (input, output) = (SyncIoBridge::new(input), SyncIoBridge::new(output));
+                let connection = serve(workspaces.clone(), hello(), key.clone(), input, output);
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("Connection failed: {e}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Speaks the protocol with a bot until either side hangs up
+async fn serve(
+    workspaces: Arc<Workspaces>,
+    hello: Hello,
+    key: Option<Key>,
+    input: impl Read + Send + 'static,
+    output: impl Write + Send + 'static,
+) -> Result<(), Error> {
+    let challenge = hello.challenge;
+
+    let (in_tx, in_rx) = mpsc::channel(4);
+    // Unbounded so that progress is never held up by a slow reader
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    let (session_tx, session_rx) = oneshot::channel();
+
+    let mut handler = tokio::spawn(handle_messages(workspaces, in_rx, out_tx));
+
+    let reader_key = key.clone();
+    let mut reader = task::spawn_blocking(move || -> Result<(), Error> {
+        let mut input = BufReader::new(input);
+
+        let Some(Greeting { challenge: bot }) = read_frame::<Greeting>(&mut input)? else {
+            return Ok(());
+        };
+        let session = Session {
+            worker: challenge,
+            bot,
+        };
+        let _ = session_tx.send(session);
+        while let Some(Signed { msg: req, mac }) = read_frame::<Signed<Request>>(&mut input)? {
+            if let Some(key) = &reader_key {
+                let authentic = mac.is_some_and(|mac| key.verify_request(&session, &req, &mac));
+                if !authentic {
+                    return Err(format!("Request {} is not authentic", req.id).into());
                 }
-                false
-            }) {
-                break;
             }
-            let req = req?;
             in_tx.blocking_send(req)?;
         }
         Ok(())
     });
 
-    io.spawn_blocking(move || {
-        let stdout = std::io::stdout();
-        let mut stdout = BufWriter::new(stdout);
+    let mut writer = task::spawn_blocking(move || -> Result<(), Error> {
+        let mut output = BufWriter::new(output);
 
-        while let Some(res) = out_rx.blocking_recv() {
-            bincode::serialize_into(&mut stdout, &res)?;
-            stdout.flush()?;
+        write_frame(&mut output, &hello)?;
+        // Nothing can be signed before the bot's challenge arrives, nor requested
+        let Ok(session) = session_rx.blocking_recv() else {
+            return Ok(());
+        };
+        while let Some(msg) = out_rx.blocking_recv() {
+            let mac = key.as_ref().map(|key| key.sign_message(&session, &msg));
+            write_frame(&mut output, &Signed { msg, mac })?;
         }
         Ok(())
     });
 
-    select! {
-        Some(task) = io.join_next() => {
-            task?
-        },
-        handler = handler => {
-            handler?
-        }
-    }
+    // The reader decides when the connection is over, unless something else fails first
+    let res = select! {
+        res = &mut reader => res?,
+        Err(e) = async { (&mut writer).await? } => Err(e),
+        Err(e) = &mut handler => Err(e.into()),
+    };
+    // Nobody is left to respond to
+    handler.abort();
+    res
 }
 
+/// Handles requests as they come in, as many at a time as `workspaces` allows
 async fn handle_messages(
+    workspaces: Arc<Workspaces>,
     mut in_rx: mpsc::Receiver<Request>,
-    out_tx: mpsc::Sender<Response>,
-) -> Result<(), Error> {
+    out_tx: mpsc::UnboundedSender<Message>,
+) {
+    // Dropped along with the handler when the connection closes, abandoning the requests
+    let mut jobs = JoinSet::new();
     while let Some(req) = in_rx.recv().await {
-        write(format!("{RUNNER_DIR}/src/lib.rs"), req.code).await?;
-        let res = benchmark(&req.inputs).await?;
-        let res = Response {
-            id: req.id,
-            outputs: res.0,
-            times: res.1,
-        };
-        out_tx.send(res).await?;
+        let (workspaces, out_tx) = (workspaces.clone(), out_tx.clone());
+        jobs.spawn(async move {
+            let id = req.id;
+            let progress = |progress| {
+                // Only fails once the connection has closed, which ends this handler anyway
+                let _ = out_tx.send(Message::Progress { id, progress });
+            };
+            progress(Progress::Accepted);
+            let runner = template(req.language).runner;
+            let outcome = match workspaces.checkout(runner).await {
+                Ok(workspace) => bench::benchmark(&workspace, &req, progress).await,
+                Err(e) => Err(e),
+            }
+            .unwrap_or_else(|e| Outcome::InfrastructureError(e.to_string()));
+            let _ = out_tx.send(Message::Response(Response { id, outcome }));
+        });
+        while jobs.try_join_next().is_some() {}
     }
-
-    Ok(())
+    jobs.join_all().await;
 }
 
-async fn benchmark(inputs: &[Vec<u8>]) -> Result<(Vec<Result<i64, String>>, Vec<u64>), Error> {
-    let inputs = inputs
-        .iter()
-        .enumerate()
-        .map(|(i, input)| (format!("INPUT_{i}"), OsStr::from_bytes(input)));
-    let output = Command::new("cargo")
-        .args(&[
-            "bench",
-            "--bench",
-            "bench",
-            "--quiet",
-            "--offline",
-            "--",
-            "--nocapture",
-        ])
-        .envs(inputs)
-        .current_dir(RUNNER_DIR)
-        .output()
-        .await?;
-    if output.status.success() {
-        let mut solutions = Vec::with_capacity(3);
-        let mut times = Vec::with_capacity(3);
-        let output = str::from_utf8(&output.stdout)?;
-        for line in output.lines() {
-            let line = line.trim_start();
-            let (thing, rest) = line.split_once(':').unwrap();
-            let rest = rest.trim();
-            match thing {
-                "Solution" => solutions.push(Ok(rest.parse().unwrap())),
-                "Instructions" => {
-                    let val = rest.split_once('|').unwrap().0;
-                    times.push(val.parse().unwrap());
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpStream};
+
+    use super::*;
+    use crate::bench::tests::{LIMITS, runner_crate, test_dir};
+
+    /// Serves a single connection on localhost, returning its address and how it went
+    async fn serve_once(name: &str, key: Key) -> (String, task::JoinHandle<Result<(), String>>) {
+        let dir = test_dir(name);
+        runner_crate(&dir.join("runner"), "");
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = Workspaces::new(runners, dir.join("jobs"), 1, None);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (input, output) = stream.into_split();
+            let (input, output) = (SyncIoBridge::new(input), SyncIoBridge::new(output));
+            let hello = Hello::new("rustc".into(), None, &[], false);
+            let res = serve(workspaces, hello, Some(key), input, output).await;
+            fs::remove_dir_all(&dir).unwrap();
+            res.map_err(|e| e.to_string())
+        });
+        (addr, server)
+    }
+
+    fn request(id: i64) -> Request {
+        Request {
+            id,
+            part: Some(1),
+            inputs: vec![b"42".to_vec()],
+            code: b"pub fn run(_: &str) -> u8 { nope }".to_vec(),
+            language: Language::Rust,
+            limits: LIMITS,
+            callgrind_args: Vec::new(),
+            wall_clock: None,
+        }
+    }
+
+    /// Reads the worker's hello and greets it back like the bot does
+    fn handshake(stream: &mut TcpStream) -> Session {
+        let hello = read_frame::<Hello>(stream).unwrap().unwrap();
+        let challenge = worker::auth::challenge();
+        write_frame(stream, &Greeting { challenge }).unwrap();
+        Session {
+            worker: hello.challenge,
+            bot: challenge,
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_answered() {
+        let key = Key::new(b"hunter2");
+        let (addr, server) = serve_once("signed", key.clone()).await;
+
+        let client = task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let session = handshake(&mut stream);
+            let req = request(2);
+            let mac = key.sign_request(&session, &req);
+            write_frame(
+                &mut stream,
+                &Signed {
+                    msg: req,
+                    mac: Some(mac),
+                },
+            )
+            .unwrap();
+            loop {
+                let Signed { msg, mac } =
+                    read_frame::<Signed<Message>>(&mut stream).unwrap().unwrap();
+                // Progress included
+                let mac = mac.expect("the message is not signed");
+                assert!(key.verify_message(&session, &msg, &mac));
+                if let Message::Response(res) = msg {
+                    return res;
                 }
-                _ => (),
             }
+        });
+        let res = client.await.unwrap();
+
+        assert_eq!(res.id, 2);
+        assert!(
+            matches!(res.outcome, Outcome::CompileError(_)),
+            "got {:?}",
+            res.outcome
+        );
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsigned_requests_are_refused() {
+        let (addr, server) = serve_once("unsigned", Key::new(b"hunter2")).await;
+
+        let client = task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let session = handshake(&mut stream);
+            // Signed, but not with the worker's secret
+            let req = request(3);
+            let mac = Key::new(b"hunter3").sign_request(&session, &req);
+            write_frame(
+                &mut stream,
+                &Signed {
+                    msg: req,
+                    mac: Some(mac),
+                },
+            )
+            .unwrap();
+            read_frame::<Signed<Message>>(&mut stream)
+        });
+        let err = server.await.unwrap().unwrap_err();
+
+        assert!(err.contains("not authentic"), "got {err}");
+        // The connection is closed without a word about the request
+        assert!(matches!(client.await.unwrap(), Ok(None) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_isolated() {
+        let dir = test_dir("concurrent");
+        runner_crate(&dir.join("runner"), "");
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = Workspaces::new(runners, dir.join("jobs"), 2, None);
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let handler = tokio::spawn(handle_messages(workspaces, in_rx, out_tx));
+
+        // Each fails to compile, naming something only it has in its copy of the crate
+        for (id, name) in [(4, "first"), (5, "second")] {
+            let code = format!("pub fn run(_: &str) -> u8 {{ {name} }}");
+            in_tx
+                .send(Request {
+                    code: code.into(),
+                    ..request(id)
+                })
+                .await
+                .unwrap();
         }
-        Ok((solutions, times))
-    } else {
-        Err(String::from_utf8_lossy(&output.stderr).into())
+        drop(in_tx);
+        let mut diagnostics = Vec::new();
+        while let Some(msg) = out_rx.recv().await {
+            if let Message::Response(res) = msg {
+                match res.outcome {
+                    Outcome::CompileError(err) => diagnostics.push((res.id, err)),
+                    outcome => panic!("expected a compile error, got {outcome:?}"),
+                }
+            }
+        }
+        handler.await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        diagnostics.sort();
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].1.contains("`first`"));
+        assert!(!diagnostics[0].1.contains("`second`"));
+        assert!(diagnostics[1].1.contains("`second`"));
+        assert!(!diagnostics[1].1.contains("`first`"));
     }
 }
@@ -0,0 +1,351 @@
+//! Telling which functions a submission provides from its code, without compiling it
+
+use serde::{Deserialize, Serialize};
+
+use crate::Language;
+
+/// The functions a submission provides to be benchmarked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    /// A single `run` taking the input and returning the answer
+    Run,
+    /// `parse` taking the input, and `solve` taking what `parse` returned
+    Split,
+    /// `part1` and `part2`, each taking the input and returning that part's answer
+    Parts,
+}
+
+impl Shape {
+    /// A `run` wins over anything else, as helpers next to it may be named anything
+    pub fn detect(language: Language, code: &[u8]) -> Self {
+        let code = String::from_utf8_lossy(code);
+        let exported = exported_functions(language, &code);
+        let defines = |name: &str| exported.contains(&name);
+        if defines("run") {
+            Shape::Run
+        } else if defines("part1") && defines("part2") {
+            Shape::Parts
+        } else if defines("parse") && defines("solve") {
+            Shape::Split
+        } else {
+            Shape::Run
+        }
+    }
+
+    /// Whether the submission solves both parts at once
+    pub fn solves_both(self) -> bool {
+        self == Shape::Parts
+    }
+
+    /// How many functions are benchmarked for each input
+    pub fn benchmarks(self) -> usize {
+        match self {
+            Shape::Run => 1,
+            Shape::Split | Shape::Parts => 2,
+        }
+    }
+
+    /// The runner crate feature that benchmarks this shape
+    pub fn feature(self) -> Option<&'static str> {
+        match self {
+            Shape::Run => None,
+            Shape::Split => Some("split"),
+            Shape::Parts => Some("parts"),
+        }
+    }
+}
+
+/// The functions the code exports at the top level: `pub fn` in Rust, `export fn` in Zig
+/// and any definition in C and C++
+fn exported_functions(language: Language, code: &str) -> Vec<&str> {
+    let tokens = tokens(language, code);
+    let before = |i: usize, n: usize| i.checked_sub(n).map(|i| tokens[i]);
+    let mut names = Vec::new();
+    // Whether each open block is still the top level, like `extern "C"` in C++
+    let mut blocks = Vec::new();
+    for (i, &token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('{') => blocks.push(
+                language == Language::Cpp
+                    && before(i, 1) == Some(Token::Literal)
+                    && before(i, 2) == Some(Token::Ident("extern")),
+            ),
+            Token::Punct('}') => {
+                blocks.pop();
+            }
+            Token::Ident(name) if blocks.iter().all(|&top_level| top_level) => {
+                let exported = match language {
+                    Language::Rust => {
+                        // Past qualifiers such as `const` and `extern "C"`
+                        let qualified = |n| {
+                            matches!(
+                                before(i, n),
+                                Some(
+                                    Token::Ident("const" | "async" | "unsafe" | "extern")
+                                        | Token::Literal
+                                )
+                            )
+                        };
+                        let pub_at = (2..).find(|&n| !qualified(n)).unwrap();
+                        before(i, 1) == Some(Token::Ident("fn"))
+                            && before(i, pub_at) == Some(Token::Ident("pub"))
+                    }
+                    Language::Zig => {
+                        before(i, 1) == Some(Token::Ident("fn"))
+                            && before(i, 2) == Some(Token::Ident("export"))
+                    }
+                    // Preceded by the return type, unlike calls and macros
+                    Language::C | Language::Cpp => {
+                        tokens.get(i + 1) == Some(&Token::Punct('('))
+                            && before(i, 1) != Some(Token::Ident("define"))
+                            && matches!(
+                                before(i, 1),
+                                Some(Token::Ident(_) | Token::Punct('*' | '&' | '>'))
+                            )
+                    }
+                };
+                if exported {
+                    names.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Just enough of a token to tell functions apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    /// Also numbers
+    Ident(&'a str),
+    Punct(char),
+    /// Any string or character literal
+    Literal,
+}
+
+/// Splits code into tokens, leaving out whitespace and comments
+fn tokens(language: Language, code: &str) -> Vec<Token<'_>> {
+    let bytes = code.as_bytes();
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    // Where the first `end` at or after `from` ends
+    let skip_to = |from: usize, end: &[u8]| {
+        bytes[from.min(bytes.len())..]
+            .windows(end.len())
+            .position(|w| w == end)
+            .map_or(bytes.len(), |at| from + at + end.len())
+    };
+    // Where a quoted literal starting at `from` ends, past escaped quotes
+    let skip_quoted = |from: usize, quote: u8| {
+        let mut i = from + 1;
+        while i < bytes.len() && bytes[i] != quote {
+            i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+        (i + 1).min(bytes.len())
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        if rest.starts_with(b"//") {
+            i = skip_to(i, b"\n");
+        } else if rest.starts_with(b"\\\\") {
+            // A line of a multiline string in Zig
+            tokens.push(Token::Literal);
+            i = skip_to(i, b"\n");
+        } else if rest.starts_with(b"/*") {
+            // Only nested in Rust
+            let mut depth = 0;
+            while i < bytes.len() {
+                if bytes[i..].starts_with(b"/*") && (depth == 0 || language == Language::Rust) {
+                    depth += 1;
+                    i += 2;
+                } else if bytes[i..].starts_with(b"*/") {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+        } else if rest[0] == b'"' {
+            tokens.push(Token::Literal);
+            i = skip_quoted(i, b'"');
+        } else if rest[0] == b'\'' {
+            // A character literal, or a lifetime or label in Rust
+            let len = match rest.get(1) {
+                Some(b'\\') => None,
+                Some(&b) => Some(match b.leading_ones() {
+                    0 => 1,
+                    n => n as usize,
+                }),
+                None => Some(0),
+            };
+            match len {
+                Some(len) if rest.get(1 + len) != Some(&b'\'') => {
+                    tokens.push(Token::Punct('\''));
+                    i += 1;
+                }
+                _ => {
+                    tokens.push(Token::Literal);
+                    i = skip_quoted(i, b'\'');
+                }
+            }
+        } else if is_ident(rest[0]) {
+            let start = i;
+            while i < bytes.len() && is_ident(bytes[i]) {
+                i += 1;
+            }
+            let ident = &code[start..i];
+            let hashes = bytes[i..].iter().take_while(|&&b| b == b'#').count();
+            if matches!(ident, "r" | "br" | "cr") && bytes.get(i + hashes) == Some(&b'"') {
+                // A raw string in Rust, ending in as many `#` as it started with
+                let end = [&b"\""[..], &vec![b'#'; hashes]].concat();
+                tokens.push(Token::Literal);
+                i = skip_to(i + hashes + 1, &end);
+            } else if matches!(ident, "R" | "LR" | "uR" | "UR" | "u8R")
+                && bytes.get(i) == Some(&b'"')
+            {
+                // A raw string in C++, ending in `)`, its delimiter and `"`
+                let open = skip_to(i, b"(").max(i + 2);
+                let end = [&b")"[..], &bytes[i + 1..open - 1], b"\""].concat();
+                tokens.push(Token::Literal);
+                i = skip_to(open, &end);
+            } else {
+                tokens.push(Token::Ident(ident));
+            }
+        } else {
+            if rest[0].is_ascii_punctuation() {
+                tokens.push(Token::Punct(rest[0].into()));
+            }
+            i += 1;
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(language: Language, code: &str) -> Shape {
+        Shape::detect(language, code.as_bytes())
+    }
+
+    #[test]
+    fn run_wins_over_helpers() {
+        let code = r#"
+pub fn parse_line(line: &str) -> u32 { line.len() as u32 }
+pub fn solve_line(n: u32) -> u32 { n * 2 }
+pub fn parse(input: &str) -> Vec<u32> { input.lines().map(parse_line).collect() }
+pub fn solve(lines: Vec<u32>) -> u32 { lines.into_iter().map(solve_line).sum() }
+pub fn run(input: &str) -> u32 { solve(parse(input)) }
+"#;
+        assert_eq!(detect(Language::Rust, code), Shape::Run);
+    }
+
+    #[test]
+    fn whole_names_are_matched() {
+        let code = r#"
+pub fn parse_line(line: &str) -> u32 { line.len() as u32 }
+pub fn solve_all(input: &str) -> u32 { input.lines().map(parse_line).sum() }
+pub fn part1_helper() {}
+pub fn part2s() {}
+"#;
+        assert_eq!(detect(Language::Rust, code), Shape::Run);
+    }
+
+    #[test]
+    fn comments_and_literals_are_ignored() {
+        let code = r##"
+// pub fn part1(input: &str) -> u32 { 0 }
+/* pub fn part2(input: &str) -> u32 { /* nested */ 0 } */
+const TEMPLATE: &str = r#"pub fn parse(input: &str) {"#;
+const BRACE: char = '{';
+pub fn solve<'a>(input: &'a str) -> &'a str { input }
+"##;
+        assert_eq!(detect(Language::Rust, code), Shape::Run);
+    }
+
+    #[test]
+    fn methods_are_not_exported() {
+        let code = r#"
+pub struct Grid(Vec<u8>);
+impl Grid {
+    pub fn parse(input: &str) -> Self { Grid(input.into()) }
+}
+mod inner {
+    pub fn part1() {}
+}
+pub fn solve(input: &str) -> usize { Grid::parse(input).0.len() }
+pub fn part2() {}
+"#;
+        assert_eq!(detect(Language::Rust, code), Shape::Run);
+    }
+
+    #[test]
+    fn every_shape_is_detected_in_rust() {
+        let split = "pub fn parse(i: &str) -> &str { i }\npub const fn solve(i: &str) {}";
+        assert_eq!(detect(Language::Rust, split), Shape::Split);
+        let parts = "pub fn part1(i: &str) {}\npub extern \"C\" fn part2(i: &str) {}";
+        assert_eq!(detect(Language::Rust, parts), Shape::Parts);
+    }
+
+    #[test]
+    fn c_helpers_and_calls_are_told_apart() {
+        let code = r#"
+#include <stddef.h>
+#define solve(x) (x)
+
+
+static int parse(const unsigned char *s) { return s[0]; }
+
+size_t run(const unsigned char *input, size_t len, char *out, size_t cap) {
+    return solve(parse(input));
+}
+"#;
+        assert_eq!(detect(Language::C, code), Shape::Run);
+        let split = "/* not /* nested */ void *parse(const char *s, size_t n) { return 0; }\n\
+                     size_t solve(void *p, char *out, size_t cap) { return 0; }";
+        assert_eq!(detect(Language::C, split), Shape::Split);
+    }
+
+    #[test]
+    fn extern_c_blocks_are_the_top_level_in_cpp() {
+        let code = r#"
+namespace helpers { size_t run(const char *s) { return 0; } }
+extern "C" {
+size_t part1(const unsigned char *input, size_t len, char *out, size_t cap) { return 0; }
+size_t part2(const unsigned char *input, size_t len, char *out, size_t cap) { return 0; }
+}
+"#;
+        assert_eq!(detect(Language::Cpp, code), Shape::Parts);
+    }
+
+    #[test]
+    fn only_exported_zig_functions_count() {
+        let code = r#"
+const help =
+    \\export fn run() void {}
+;
+fn parse(input: []const u8) usize { return input.len; }
+export fn solve(input: [*]const u8, len: usize) usize { return parse(input[0..len]); }
+"#;
+        assert_eq!(detect(Language::Zig, code), Shape::Run);
+        let parts = "export fn part1() void {}\npub export fn part2() void {}";
+        assert_eq!(detect(Language::Zig, parts), Shape::Parts);
+    }
+
+    #[test]
+    fn cpp_raw_strings_are_literals() {
+        let code = r#"
+const char *TEMPLATE = R"x(size_t run(const char *s) { return 0; })x";
+size_t parse(const unsigned char *input, size_t len) { return len; }
+size_t solve(size_t parsed, char *out, size_t cap) { return 0; }
+"#;
+        assert_eq!(detect(Language::Cpp, code), Shape::Split);
+    }
+}
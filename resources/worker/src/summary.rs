@@ -0,0 +1,474 @@
+//! Reading results out of the files written during a benchmark run.
+//!
+//! The harness, iai-callgrind and valgrind all run as the same user as the submission, so
+//! a submission could have written any of these files itself, metrics included. Nothing
+//! read here is trusted any more than the submission: answers are checked against the
+//! consensus, and anything missing or malformed makes the results invalid. Only the
+//! submission of the request can write them though, as its dir is private to it, see
+//! [`job`](crate::job).
+//!
+//! Summaries are copied to a dir only the worker can write to as soon as they are saved,
+//! and read from there. A submission that changes one after that, say from a process it
+//! left running, gets its results rejected.
+
+use std::{
+    collections::HashMap,
+    fs,
+    fs::OpenOptions,
+    io,
+    io::Read,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, de::IgnoredAny};
+use worker::{Metrics, Timing};
+
+/// The name of the instruction count among the metrics
+pub const INSTRUCTIONS: &str = "Ir";
+/// The name DHAT gives the most bytes the heap held at once
+const PEAK_HEAP: &str = "AtTGmaxBytes";
+
+/// How many times valgrind runs every benchmark, once with callgrind and once with DHAT.
+/// The harness records an answer each time.
+pub const VALGRIND_RUNS: usize = 2;
+
+/// What valgrind measured of a single benchmark
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measured {
+    pub metrics: Metrics,
+    /// In bytes, including the input the harness holds
+    pub peak_heap: u64,
+}
+
+#[derive(Deserialize)]
+struct BenchmarkSummary {
+    id: Option<String>,
+    callgrind_summary: Option<CallgrindSummary>,
+    #[serde(default)]
+    tool_summaries: Vec<ToolSummary>,
+}
+
+#[derive(Deserialize)]
+struct CallgrindSummary {
+    callgrind_run: CallgrindRun,
+}
+
+#[derive(Deserialize)]
+struct CallgrindRun {
+    total: CallgrindTotal,
+}
+
+#[derive(Deserialize)]
+struct CallgrindTotal {
+    summary: HashMap<String, MetricsDiff>,
+}
+
+/// A run of any valgrind tool but callgrind
+#[derive(Deserialize)]
+struct ToolSummary {
+    summaries: ToolRun,
+}
+
+#[derive(Deserialize)]
+struct ToolRun {
+    total: ToolTotal,
+}
+
+/// Only DHAT's totals are of interest
+#[derive(Deserialize)]
+enum ToolTotal {
+    None,
+    ErrorSummary(IgnoredAny),
+    DhatSummary(HashMap<String, MetricsDiff>),
+    CallgrindSummary(IgnoredAny),
+}
+
+#[derive(Deserialize)]
+struct MetricsDiff {
+    metrics: EitherOrBoth,
+}
+
+/// The new value, the baseline value or both. Runs never have a baseline to compare with.
+#[derive(Deserialize)]
+enum EitherOrBoth {
+    Left(u64),
+    Right(IgnoredAny),
+    Both(u64, IgnoredAny),
+}
+
+/// Matches the answers recorded by the harness to their inputs, grouped by the benchmark
+/// that recorded them. Every input must have the same answer recorded once per run of the
+/// benchmark. Records for unknown inputs, extra records, differing records and missing
+/// records are all rejected.
+pub fn parse_answers(
+    records: &str,
+    count: usize,
+    runs: usize,
+) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut benches = HashMap::<_, Vec<Option<(String, usize)>>>::new();
+    for record in records.lines() {
+        let mut fields = record.splitn(3, '\t');
+        let (Some(bench), Some(input), Some(answer)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("Malformed result record {record:?}"));
+        };
+        let index = input_index(input, "INPUT_", 1, count)
+            .ok_or_else(|| format!("Result recorded for unknown input {input:?}"))?;
+        let answers = benches
+            .entry(bench.to_owned())
+            .or_insert_with(|| vec![None; count]);
+        match &mut answers[index] {
+            None => answers[index] = Some((answer.to_owned(), 1)),
+            Some((first, _)) if first != answer => {
+                return Err(format!("Differing {bench} results recorded for {input}"));
+            }
+            Some((_, recorded)) if *recorded == runs => {
+                return Err(format!("Duplicate {bench} result recorded for {input}"));
+            }
+            Some((_, recorded)) => *recorded += 1,
+        }
+    }
+    let answers = complete(benches, |bench, i| {
+        format!("No {bench} result recorded for INPUT_{}", i + 1)
+    })?;
+    answers
+        .into_iter()
+        .map(|(bench, answers)| {
+            let answers = answers
+                .into_iter()
+                .enumerate()
+                .map(|(i, (answer, recorded))| match recorded == runs {
+                    true => Ok(answer),
+                    false => Err(format!(
+                        "Only {recorded} of {runs} {bench} results recorded for INPUT_{}",
+                        i + 1
+                    )),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((bench, answers))
+        })
+        .collect()
+}
+
+/// Matches the timings recorded by the wall-clock harness to their inputs, grouped by the
+/// benchmark that recorded them
+pub fn parse_timings(records: &str, count: usize) -> Result<HashMap<String, Vec<Timing>>, String> {
+    let mut benches = HashMap::<_, Vec<_>>::new();
+    for record in records.lines() {
+        let fields = record.split('\t').collect::<Vec<_>>();
+        let &[bench, input, median, spread, samples] = fields.as_slice() else {
+            return Err(format!("Malformed timing record {record:?}"));
+        };
+        let index = input_index(input, "INPUT_", 1, count)
+            .ok_or_else(|| format!("Timing recorded for unknown input {input:?}"))?;
+        let malformed = || format!("Malformed timing record {record:?}");
+        let timing = Timing {
+            median: Duration::from_nanos(median.parse().map_err(|_| malformed())?),
+            spread: Duration::from_nanos(spread.parse().map_err(|_| malformed())?),
+            samples: samples.parse().map_err(|_| malformed())?,
+        };
+        let timings = benches
+            .entry(bench.to_owned())
+            .or_insert_with(|| vec![None; count]);
+        if timings[index].replace(timing).is_some() {
+            return Err(format!("Duplicate {bench} timing recorded for {input}"));
+        }
+    }
+    complete(benches, |bench, i| {
+        format!("No {bench} timing recorded for INPUT_{}", i + 1)
+    })
+}
+
+/// Reads what iai-callgrind saved under `home`. It is grouped by benchmark, such as `run`
+/// or `parse`, and is in input order within each group. Every benchmark has at least an
+/// instruction count and a peak heap size.
+pub fn read_summaries(home: &Path, count: usize) -> Result<HashMap<String, Vec<Measured>>, String> {
+    let mut paths = Vec::new();
+    find_summaries(home, &mut paths).map_err(|e| format!("Failed to find summaries: {e}"))?;
+
+    let mut benches = HashMap::<_, Vec<_>>::new();
+    for path in paths {
+        let summary = fs::read(&path).map_err(|e| format!("Failed to read summary: {e}"))?;
+        let summary: BenchmarkSummary = serde_json::from_slice(&summary)
+            .map_err(|e| format!("Malformed summary {}: {e}", path.display()))?;
+
+        let id = summary.id.unwrap_or_default();
+        let (bench, index) = id
+            .rsplit_once('_')
+            .and_then(|(bench, i)| Some((bench, input_index(i, "", 0, count)?)))
+            .ok_or_else(|| format!("Summary for unknown benchmark {id:?}"))?;
+        let metrics = summary
+            .callgrind_summary
+            .map(|s| {
+                s.callgrind_run
+                    .total
+                    .summary
+                    .into_iter()
+                    .filter_map(|(event, diff)| Some((event, diff.metrics.new_value()?)))
+                    .collect::<Metrics>()
+            })
+            .filter(|metrics| metrics.contains_key(INSTRUCTIONS))
+            .ok_or_else(|| format!("Summary for {id} has no instruction count"))?;
+        let peak_heap = summary
+            .tool_summaries
+            .into_iter()
+            .find_map(|tool| match tool.summaries.total {
+                ToolTotal::DhatSummary(summary) => summary.get(PEAK_HEAP)?.metrics.new_value(),
+                _ => None,
+            })
+            .ok_or_else(|| format!("Summary for {id} has no peak heap size"))?;
+
+        let values = benches
+            .entry(bench.to_owned())
+            .or_insert_with(|| vec![None; count]);
+        if values[index]
+            .replace(Measured { metrics, peak_heap })
+            .is_some()
+        {
+            return Err(format!("Duplicate summary for {id}"));
+        }
+    }
+    complete(benches, |bench, i| format!("No summary for {bench}_{i}"))
+}
+
+/// Checks that every benchmark has a value for every input
+fn complete<T>(
+    benches: HashMap<String, Vec<Option<T>>>,
+    missing: impl Fn(&str, usize) -> String,
+) -> Result<HashMap<String, Vec<T>>, String> {
+    benches
+        .into_iter()
+        .map(|(bench, values)| {
+            let values = values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| value.ok_or_else(|| missing(&bench, i)))
+                .collect::<Result<_, _>>()?;
+            Ok((bench, values))
+        })
+        .collect()
+}
+
+impl EitherOrBoth {
+    fn new_value(&self) -> Option<u64> {
+        match *self {
+            EitherOrBoth::Left(new) | EitherOrBoth::Both(new, _) => Some(new),
+            EitherOrBoth::Right(_) => None,
+        }
+    }
+}
+
+/// Parses names like `INPUT_1` into a zero based index
+fn input_index(name: &str, prefix: &str, first: usize, count: usize) -> Option<usize> {
+    let n = name.strip_prefix(prefix)?.parse::<usize>().ok()?;
+    n.checked_sub(first).filter(|&i| i < count)
+}
+
+/// Copies every summary iai-callgrind has finished saving under `home` to the same place
+/// under `copies`, unless it already has been, returning how many have been so far
+pub fn copy_summaries(home: &Path, copies: &Path) -> usize {
+    let mut paths = Vec::new();
+    // The home directory only exists once the first benchmark has started
+    let _ = find_summaries(home, &mut paths);
+    paths
+        .iter()
+        .filter(|path| {
+            let copy = copies.join(path.strip_prefix(home).expect("found under home"));
+            copy.exists() || copy_summary(path, &copy).is_ok()
+        })
+        .count()
+}
+
+/// Summaries are only copied once they are complete, which is when they are valid JSON
+fn copy_summary(path: &Path, copy: &Path) -> Result<(), String> {
+    let summary = read_file(path).map_err(|e| e.to_string())?;
+    serde_json::from_slice::<IgnoredAny>(&summary).map_err(|e| e.to_string())?;
+    fs::create_dir_all(copy.parent().expect("summaries are in a dir"))
+        .and_then(|()| fs::write(copy, summary))
+        .map_err(|e| e.to_string())
+}
+
+/// Checks that the summaries under `home` are still the ones copied to `copies`
+pub fn check_copies(home: &Path, copies: &Path) -> Result<(), String> {
+    let mut paths = Vec::new();
+    find_summaries(copies, &mut paths).map_err(|e| format!("Failed to find summaries: {e}"))?;
+    for copy in paths {
+        let path = copy.strip_prefix(copies).expect("found under copies");
+        let saved = fs::read(&copy).map_err(|e| format!("Failed to read summary: {e}"))?;
+        if read_file(&home.join(path)).ok() != Some(saved) {
+            return Err(format!(
+                "Summary {} changed after it was saved",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file at `path` without following a symlink the submission put there
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?
+        .read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn find_summaries(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_summaries(&path, paths)?;
+        } else if file_type.is_file() && entry.file_name() == "summary.json" {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_summary(home: &Path, id: &str, summary: &str, peak_heap: u64) {
+        let dir = home.join(id);
+        fs::create_dir_all(&dir).unwrap();
+        let dhat = format!(
+            r#"{{"tool": "DHAT", "log_paths": [], "out_paths": [], "summaries": {{"segments": [],
+                "total": {{"DhatSummary": {{"AtTGmaxBytes": {{"metrics": {{"Left": {peak_heap}}}}}}}}}}}}}"#
+        );
+        let summary = format!(
+            r#"{{"id": "{id}", "callgrind_summary": {{"callgrind_run": {{"total": {{"summary": {{{summary}}}}}}}}},
+                "tool_summaries": [{dhat}]}}"#
+        );
+        fs::write(dir.join("summary.json"), summary).unwrap();
+    }
+
+    #[test]
+    fn answers_are_recorded_once_per_run() {
+        let records = "run\tINPUT_1\t42\nrun\tINPUT_1\t42\n";
+        let answers = parse_answers(records, 1, 2).unwrap().remove("run").unwrap();
+        assert_eq!(answers, ["42"]);
+
+        let err = parse_answers("run\tINPUT_1\t42\n", 1, 2).unwrap_err();
+        assert_eq!(err, "Only 1 of 2 run results recorded for INPUT_1");
+        let err = parse_answers("run\tINPUT_1\t42\nrun\tINPUT_1\t43\n", 1, 2).unwrap_err();
+        assert_eq!(err, "Differing run results recorded for INPUT_1");
+        let err = parse_answers(&records.repeat(2), 1, 2).unwrap_err();
+        assert_eq!(err, "Duplicate run result recorded for INPUT_1");
+    }
+
+    #[test]
+    fn every_metric_is_read() {
+        let home =
+            std::env::temp_dir().join(format!("ferris-elf-test-summary-{}", std::process::id()));
+        write_summary(
+            &home,
+            "run_0",
+            r#""Ir": {"metrics": {"Left": 100}, "diffs": null},
+                "EstimatedCycles": {"metrics": {"Left": 140}, "diffs": null},
+                "D1mr": {"metrics": {"Both": [3, 2]}, "diffs": null},
+                "RamHits": {"metrics": {"Right": 1}, "diffs": null}"#,
+            4096,
+        );
+        write_summary(
+            &home,
+            "run_1",
+            r#""Ir": {"metrics": {"Left": 7}, "diffs": null}"#,
+            512,
+        );
+        let measured = read_summaries(&home, 2);
+        fs::remove_dir_all(&home).unwrap();
+
+        let measured = measured.unwrap().remove("run").unwrap();
+        let first = Measured {
+            metrics: Metrics::from([
+                ("Ir".into(), 100),
+                ("EstimatedCycles".into(), 140),
+                ("D1mr".into(), 3),
+            ]),
+            peak_heap: 4096,
+        };
+        let second = Measured {
+            metrics: Metrics::from([("Ir".into(), 7)]),
+            peak_heap: 512,
+        };
+        assert_eq!(measured, [first, second]);
+    }
+
+    #[test]
+    fn timings_are_matched_to_inputs() {
+        let records = "solve\tINPUT_2\t1500\t20\t97\nsolve\tINPUT_1\t900\t4\t100\n";
+        let timings = parse_timings(records, 2).unwrap().remove("solve").unwrap();
+        assert_eq!(timings[0].median, Duration::from_nanos(900));
+        assert_eq!(timings[1].spread, Duration::from_nanos(20));
+        assert_eq!(timings[1].samples, 97);
+
+        let err = parse_timings("solve\tINPUT_1\t900\t4\t100\n", 2).unwrap_err();
+        assert_eq!(err, "No solve timing recorded for INPUT_2");
+        assert!(parse_timings("solve\tINPUT_1\tfast\t4\t100\n", 1).is_err());
+    }
+
+    #[test]
+    fn instruction_counts_are_required() {
+        let home =
+            std::env::temp_dir().join(format!("ferris-elf-test-no-ir-{}", std::process::id()));
+        write_summary(
+            &home,
+            "run_0",
+            r#""EstimatedCycles": {"metrics": {"Left": 140}, "diffs": null}"#,
+            4096,
+        );
+        let measured = read_summaries(&home, 1);
+        fs::remove_dir_all(&home).unwrap();
+
+        assert_eq!(
+            measured.unwrap_err(),
+            "Summary for run_0 has no instruction count"
+        );
+    }
+
+    #[test]
+    fn summaries_changed_after_saving_are_noticed() {
+        let dir =
+            std::env::temp_dir().join(format!("ferris-elf-test-copies-{}", std::process::id()));
+        let (home, copies) = (dir.join("home"), dir.join("copies"));
+        write_summary(
+            &home,
+            "run_0",
+            r#""Ir": {"metrics": {"Left": 100}, "diffs": null}"#,
+            64,
+        );
+        // Still being saved
+        fs::create_dir_all(home.join("run_1")).unwrap();
+        fs::write(home.join("run_1/summary.json"), r#"{"id": "run_1", "#).unwrap();
+
+        let copied = copy_summaries(&home, &copies);
+        let unchanged = check_copies(&home, &copies);
+        write_summary(
+            &home,
+            "run_0",
+            r#""Ir": {"metrics": {"Left": 1}, "diffs": null}"#,
+            64,
+        );
+        let changed = check_copies(&home, &copies);
+        let measured = read_summaries(&copies, 1);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied, 1);
+        assert_eq!(unchanged, Ok(()));
+        assert_eq!(
+            changed.unwrap_err(),
+            "Summary run_0/summary.json changed after it was saved"
+        );
+        let measured = measured.unwrap().remove("run").unwrap();
+        assert_eq!(measured[0].metrics[INSTRUCTIONS], 100);
+    }
+}
@@ -0,0 +1,102 @@
+//! How submissions in each language are built into a runner crate.
+//!
+//! Rust submissions become the library of the runner crate. Submissions in other languages
+//! are compiled into `libsubmission.a` first, which the native runner crate links and calls
+//! into through the C ABI. Both crates share the benchmark harness, so every language is
+//! measured the same way.
+
+use worker::Language;
+
+/// The runner crates a worker may have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Runner {
+    Rust,
+    Native,
+}
+
+/// The static library the build commands of a native submission produce
+pub const NATIVE_LIBRARY: &str = "libsubmission.a";
+
+pub struct Template {
+    pub runner: Runner,
+    /// Where the submission is written, relative to the runner crate
+    pub source: &'static str,
+    /// Compile the submission into [`NATIVE_LIBRARY`] before cargo builds the harness, in
+    /// the runner crate's dir. Rust submissions are built by cargo along with the harness.
+    pub build: &'static [&'static [&'static str]],
+    /// Libraries the submission needs besides the C library, comma separated for the native
+    /// runner's build script
+    pub link: &'static str,
+    /// Reports the version of the compiler, which fails if it isn't installed
+    pub version: &'static [&'static str],
+}
+
+/// The archiver every native language shares
+const ARCHIVE: &[&str] = &["ar", "rcs", NATIVE_LIBRARY, "submission.o"];
+
+pub fn template(language: Language) -> Template {
+    match language {
+        Language::Rust => Template {
+            runner: Runner::Rust,
+            source: "src/lib.rs",
+            build: &[],
+            link: "",
+            version: &["rustc", "--version"],
+        },
+        Language::C => Template {
+            runner: Runner::Native,
+            source: "submission.c",
+            build: &[
+                &[
+                    "cc",
+                    "-std=c17",
+                    "-O2",
+                    "-g",
+                    "-fPIC",
+                    "-c",
+                    "submission.c",
+                    "-o",
+                    "submission.o",
+                ],
+                ARCHIVE,
+            ],
+            link: "",
+            version: &["cc", "--version"],
+        },
+        Language::Cpp => Template {
+            runner: Runner::Native,
+            source: "submission.cpp",
+            build: &[
+                &[
+                    "c++",
+                    "-std=c++20",
+                    "-O2",
+                    "-g",
+                    "-fPIC",
+                    "-c",
+                    "submission.cpp",
+                    "-o",
+                    "submission.o",
+                ],
+                ARCHIVE,
+            ],
+            link: "stdc++",
+            version: &["c++", "--version"],
+        },
+        Language::Zig => Template {
+            runner: Runner::Native,
+            source: "submission.zig",
+            build: &[&[
+                "zig",
+                "build-lib",
+                "-O",
+                "ReleaseFast",
+                "-fPIC",
+                "-femit-bin=libsubmission.a",
+                "submission.zig",
+            ]],
+            link: "",
+            version: &["zig", "version"],
+        },
+    }
+}
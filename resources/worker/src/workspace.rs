@@ -0,0 +1,297 @@
+//! Copies of the runner crates, so that requests can be handled side by side.
+//!
+//! Every request gets a copy of the sources of the runner crate for its language to put the
+//! submission in. Cargo only lets one build use a target dir at a time, so every slot has a
+//! target dir of its own, seeded once from the one the runner crate was built in. That reuses
+//! the dependencies it was built with instead of building them again for every copy.
+//!
+//! When submissions run as users of their own, each slot has its own user, see
+//! [`job`](crate::job). The copies are then writable to the group of the owner of the
+//! runner crate, who builds them. Slots are named at random in a dir that can't be listed,
+//! so that a submission can't find the copies of the others.
+
+use std::{
+    collections::HashMap,
+    fs::{self, Permissions},
+    os::unix::fs::{PermissionsExt, chown},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    process::Command,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task,
+};
+
+use crate::{
+    Error,
+    job::{User, random_name, run_as},
+    template::Runner,
+};
+
+/// Shared by every request a worker handles
+pub struct Workspaces {
+    /// The runner crates this worker has
+    runner_dirs: HashMap<Runner, PathBuf>,
+    /// Where the copies go, one dir per slot
+    dir: PathBuf,
+    /// The name of the dir of every slot
+    slot_names: Vec<String>,
+    /// Limits how many requests are handled at once
+    permits: Arc<Semaphore>,
+    /// Slots not in use. The dir of a slot is reused, so artifacts in the target dir are
+    /// replaced rather than piling up for every request.
+    free: Mutex<Vec<usize>>,
+    /// The uid the submissions in the first slot run as, followed by one for every other
+    /// slot. Submissions run as the worker itself without it.
+    first_uid: Option<u32>,
+}
+
+/// The copy of the runner crate a single request is handled in
+pub struct Workspace {
+    workspaces: Arc<Workspaces>,
+    slot: usize,
+    dir: PathBuf,
+    runner: Runner,
+    runner_dir: PathBuf,
+    builder: Option<User>,
+    submitter: Option<User>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Workspaces {
+    pub fn new(
+        runner_dirs: HashMap<Runner, PathBuf>,
+        dir: PathBuf,
+        parallelism: usize,
+        first_uid: Option<u32>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            runner_dirs,
+            dir,
+            slot_names: (0..parallelism).map(|_| random_name("slot")).collect(),
+            permits: Arc::new(Semaphore::new(parallelism)),
+            free: Mutex::new((0..parallelism).rev().collect()),
+            first_uid,
+        })
+    }
+
+    /// Waits for a free slot, then fills it with a fresh copy of the runner crate
+    pub async fn checkout(self: &Arc<Self>, runner: Runner) -> Result<Workspace, Error> {
+        let runner_dir = self
+            .runner_dirs
+            .get(&runner)
+            .ok_or_else(|| format!("No {runner:?} runner crate"))?
+            .clone();
+        let builder = match self.first_uid {
+            Some(_) => Some(User::owner(&runner_dir)?),
+            None => None,
+        };
+        let permit = Arc::clone(&self.permits).acquire_owned().await?;
+        let slot = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a free slot for every permit");
+        let workspace = Workspace {
+            workspaces: Arc::clone(self),
+            slot,
+            dir: self.dir.join(&self.slot_names[slot]),
+            runner,
+            runner_dir,
+            builder,
+            submitter: self.first_uid.map(|uid| {
+                let uid = uid + slot as u32;
+                User { uid, gid: uid }
+            }),
+            _permit: permit,
+        };
+
+        let (from, to) = (workspace.runner_dir.clone(), workspace.dir.clone());
+        let dir = self.dir.clone();
+        task::spawn_blocking(move || {
+            if to.exists() {
+                fs::remove_dir_all(&to)?;
+            }
+            copy_dir(&from, &to, builder)?;
+            fs::set_permissions(&dir, Permissions::from_mode(0o711))?;
+            Ok::<_, Error>(())
+        })
+        .await??;
+        workspace.seed_target_dir().await?;
+        Ok(workspace)
+    }
+}
+
+impl Workspace {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Kept for every request in the same slot, so that builds in other slots never wait
+    /// for it
+    pub fn target_dir(&self) -> PathBuf {
+        let name = &self.workspaces.slot_names[self.slot];
+        let runner = format!("{:?}", self.runner).to_lowercase();
+        self.workspaces.dir.join(format!("{name}-{runner}-target"))
+    }
+
+    /// Who builds the submission, if not the worker itself
+    pub fn builder(&self) -> Option<User> {
+        self.builder
+    }
+
+    /// Who the submission runs as, if not the worker itself
+    pub fn submitter(&self) -> Option<User> {
+        self.submitter
+    }
+
+    /// Copies the target dir of the runner crate the first time the slot builds it, as
+    /// whoever builds the copies. A copy cut short is never used.
+    async fn seed_target_dir(&self) -> Result<(), Error> {
+        let target_dir = self.target_dir();
+        if target_dir.exists() {
+            return Ok(());
+        }
+        let partial = target_dir.with_extension("partial");
+        if partial.exists() {
+            tokio::fs::remove_dir_all(&partial).await?;
+        }
+        tokio::fs::create_dir(&partial).await?;
+        if let Some(builder) = self.builder {
+            chown(&partial, None, Some(builder.gid))?;
+            tokio::fs::set_permissions(&partial, Permissions::from_mode(0o2775)).await?;
+        }
+        let seed = self.runner_dir.join("target");
+        if seed.exists() {
+            let mut cmd = Command::new("cp");
+            let status = run_as(&mut cmd, self.builder)
+                .arg("-pR")
+                .arg(seed.join("."))
+                .arg(&partial)
+                .status()
+                .await?;
+            if !status.success() {
+                return Err(format!("Failed to copy {}: {status}", seed.display()).into());
+            }
+        }
+        tokio::fs::rename(&partial, &target_dir).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        self.workspaces.free.lock().unwrap().push(self.slot);
+    }
+}
+
+/// Copies everything but the target dir, writable to the group of `builder` if any
+fn copy_dir(from: &Path, to: &Path, builder: Option<User>) -> Result<(), Error> {
+    fs::create_dir_all(to)?;
+    if let Some(builder) = builder {
+        chown(to, None, Some(builder.gid))?;
+        fs::set_permissions(to, Permissions::from_mode(0o2775))?;
+    }
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name() == "target" {
+            continue;
+        }
+        let copy = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&path, &copy, builder)?;
+        } else {
+            fs::copy(&path, &copy)?;
+            if builder.is_some() {
+                let mode = fs::metadata(&copy)?.permissions().mode();
+                fs::set_permissions(&copy, Permissions::from_mode(mode | 0o060))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::MetadataExt, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        bench::tests::{runner_crate, test_dir},
+        job::tests::test_users,
+    };
+
+    #[tokio::test]
+    async fn slots_are_limited_and_reused() {
+        let dir = test_dir("slots");
+        runner_crate(&dir.join("runner"), "");
+        fs::create_dir_all(dir.join("runner/target/release")).unwrap();
+        fs::write(dir.join("runner/target/release/dependency"), "built").unwrap();
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = Workspaces::new(runners, dir.join("jobs"), 2, None);
+
+        let first = workspaces.checkout(Runner::Rust).await.unwrap();
+        let second = workspaces.checkout(Runner::Rust).await.unwrap();
+        assert_ne!(first.dir(), second.dir());
+        assert!(first.dir().join("Cargo.toml").exists());
+        assert!(!first.dir().join("target").exists());
+        assert_ne!(first.target_dir(), second.target_dir());
+        for workspace in [&first, &second] {
+            let seeded = workspace.target_dir().join("release/dependency");
+            assert_eq!(fs::read_to_string(seeded).unwrap(), "built");
+        }
+        assert!(
+            timeout(
+                Duration::from_millis(100),
+                workspaces.checkout(Runner::Rust)
+            )
+            .await
+            .is_err()
+        );
+
+        let reused = first.dir().to_owned();
+        fs::write(reused.join("src/lib.rs"), "leftover").unwrap();
+        drop(first);
+        let third = workspaces.checkout(Runner::Rust).await.unwrap();
+        assert!(workspaces.checkout(Runner::Native).await.is_err());
+        assert_eq!(third.dir(), reused);
+        assert_eq!(fs::read_to_string(reused.join("src/lib.rs")).unwrap(), "");
+
+        drop((second, third));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn slots_have_users_of_their_own() {
+        let Some((builder, first_uid)) = test_users() else {
+            return;
+        };
+        let dir = test_dir("users");
+        runner_crate(&dir.join("runner"), "");
+        builder.chown_all(&dir.join("runner")).unwrap();
+        let runners = HashMap::from([(Runner::Rust, dir.join("runner"))]);
+        let workspaces = Workspaces::new(runners, dir.join("jobs"), 2, Some(first_uid));
+
+        let first = workspaces.checkout(Runner::Rust).await.unwrap();
+        let second = workspaces.checkout(Runner::Rust).await.unwrap();
+        assert_eq!(first.builder(), Some(builder));
+        let source = fs::metadata(first.dir().join("src/lib.rs")).unwrap();
+        assert_eq!(source.gid(), builder.gid);
+        assert_eq!(source.mode() & 0o060, 0o060);
+        let uids = [first.submitter(), second.submitter()].map(|user| user.unwrap().uid);
+        assert_ne!(uids[0], uids[1]);
+        assert!(
+            uids.iter()
+                .all(|&uid| uid != builder.uid && uid >= first_uid)
+        );
+
+        drop((first, second));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}